@@ -0,0 +1,42 @@
+; BANKS: stores a letter in each of four banks, then selects each bank in
+; turn and prints the letter it holds; run with --machine banks.machine
+
+        SET_STACK 0x1000
+        CONST_0
+        SAVE_0                  ; bank
+
+fill:   LOAD_0
+        IMM_SAVE 0x7F00         ; bank register
+        LOAD_0
+        IMM_CONST 0x41          ; 'A'
+        ADD
+        IMM_SAVE 0x8000         ; start of the window
+        LOAD_0
+        CONST_1
+        ADD
+        DUP_B
+        SAVE_0
+        IMM_CONST 4
+        COMPARE
+        IF_UNEQUAL
+        GOTO fill
+
+        CONST_0
+        SAVE_0
+print:  LOAD_0
+        IMM_SAVE 0x7F00
+        IMM_LOAD 0x8000
+        IMM_SAVE 176            ; console data
+        LOAD_0
+        CONST_1
+        ADD
+        DUP_B
+        SAVE_0
+        IMM_CONST 4
+        COMPARE
+        IF_UNEQUAL
+        GOTO print
+
+        IMM_CONST 10            ; newline
+        IMM_SAVE 176
+        WAIT
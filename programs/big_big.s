@@ -0,0 +1,42 @@
+; BIG_BIG: 16-bit add into save_1:save_0 and 16-bit subtract into
+; save_3:save_2
+
+        SET_STACK 0x0100
+
+        ENTER
+        IMM_CONST_D 0xD1F0
+        IMM_CONST_D 0x0CBD
+        IMM_CONST_D big_add
+        CALL
+        LEAVE
+
+        ENTER
+        IMM_CONST_D 0xEF1E
+        IMM_CONST_D 0x302F
+        IMM_CONST_D big_subtract
+        CALL
+        LEAVE
+
+        WAIT
+
+big_add:
+        LOCAL_0
+        LOCAL_2
+        ADD
+        LOCAL_1
+        LOCAL_3
+        ADD_CARRY
+        SAVE_0
+        SAVE_1
+        GOBACK
+
+big_subtract:
+        LOCAL_0
+        LOCAL_2
+        SUBTRACT
+        LOCAL_1
+        LOCAL_3
+        SUB_BORROW
+        SAVE_2
+        SAVE_3
+        GOBACK
@@ -0,0 +1,22 @@
+; ECHO: copies console input to console output until the input ends
+
+        SET_STACK 0x1000
+
+poll:   IMM_LOAD 175            ; console status
+        DUP_B
+        IMM_CONST 1             ; RX_READY
+        AND
+        TEST
+        IF_UNEQUAL
+        GOTO ready
+        IMM_CONST 4             ; RX_CLOSED
+        AND
+        TEST
+        IF_EQUAL
+        GOTO poll
+        WAIT
+
+ready:  SAVE_3                  ; drop the status
+        IMM_LOAD 176            ; console data
+        IMM_SAVE 176
+        GOTO poll
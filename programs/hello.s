@@ -0,0 +1,24 @@
+; HELLO: prints a message on the console at 175 (status) / 176 (data)
+
+        SET_STACK 0x1000
+        CONST_0
+        SAVE_0                  ; index into message
+
+next:   LOAD_0
+        IMM_LOAD_OFFSET_B message
+        DUP_B
+        TEST
+        IF_EQUAL
+        GOTO done
+        IMM_SAVE 176            ; console data
+        LOAD_0
+        CONST_1
+        ADD
+        SAVE_0
+        GOTO next
+
+done:   WAIT
+
+message:
+        .ascii "Hello, world!\n"
+        .byte 0
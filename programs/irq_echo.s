@@ -0,0 +1,45 @@
+; IRQ_ECHO: ECHO driven by the console receive interrupt on line 0
+
+        SET_STACK 0x1000
+        SET_VECTORS vectors
+        CONST_0
+        SAVE_0                  ; set to 1 once the input has ended
+        IMM_CONST 0x80          ; STATUS_RX_IRQ
+        IMM_SAVE 175
+        ENABLE_INT 0b00000001
+
+idle:   LOAD_0                  ; check before waiting: an interrupt taken
+        TEST                    ; in place of WAIT returns to the WAIT
+        IF_UNEQUAL
+        GOTO done
+        WAIT
+        GOTO idle
+done:   DISABLE_INT 0b00000001
+        WAIT
+
+on_console:
+        IMM_LOAD 175
+        IMM_CONST 4             ; RX_CLOSED
+        AND
+        TEST
+        IF_UNEQUAL
+        GOTO closed
+        IMM_LOAD 175
+        CONST_1                 ; RX_READY
+        AND
+        TEST
+        IF_EQUAL
+        RETURN_INT              ; raised before the last character was read
+        IMM_LOAD 176
+        IMM_SAVE 176
+        RETURN_INT
+closed: CONST_0                 ; stop further console interrupts
+        IMM_SAVE 175
+        CONST_1
+        SAVE_0
+        SAVE_1                  ; and return with IM clear, so a WAIT
+        CONST_0                 ; returned to stops the machine
+        RETURN_INT
+
+vectors:
+        .word on_console
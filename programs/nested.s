@@ -0,0 +1,28 @@
+; NESTED: prints "ABC" through nested subroutine calls; run with
+; --machine nested.machine, which makes CALL push the return address
+
+        SET_STACK 0x1000
+        IMM_CONST_D print_abc
+        CALL
+        IMM_CONST 10            ; newline
+        IMM_SAVE 176            ; console data
+        WAIT
+
+; prints A, B and C by calling putc for each
+print_abc:
+        IMM_CONST 0x41
+        IMM_CONST_D putc
+        CALL
+        IMM_CONST 0x42
+        IMM_CONST_D putc
+        CALL
+        IMM_CONST 0x43
+        IMM_CONST_D putc
+        CALL
+        RETURN
+
+; prints the character below the return address
+putc:   LINK                    ; return address into LN
+        IMM_SAVE 176
+        UNLINK
+        RETURN
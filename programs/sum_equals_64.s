@@ -0,0 +1,31 @@
+; SUM_EQUALS_64: sets save_0 to 1 if 48 + 16 == 64, else 0
+
+        SET_STACK 0x0100
+        CONST_3
+        SAVE_0
+
+start:  ENTER
+        IMM_CONST 48
+        IMM_CONST 16
+        IMM_CONST_D sum_equals_64
+        CALL
+        LEAVE
+        WAIT
+
+sum_equals_64:
+        LOCAL_1
+        LOCAL_0
+        ADD
+        LOCAL_2
+        IMM_CONST 64
+        COMPARE
+        IF_EQUAL
+        IMM_BRANCH equal
+        IMM_BRANCH unequal
+equal:  CONST_1
+        SAVE_0
+        GOBACK
+unequal:
+        CONST_0
+        SAVE_0
+        GOBACK
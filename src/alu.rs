@@ -1,6 +1,7 @@
 use bit::BitIndex;
 use std::num::Wrapping;
 
+pub const FLAG_H: usize = 5; // half-carry flag
 pub const FLAG_O: usize = 4; // odd parity flag
 pub const FLAG_N: usize = 3; // negative flag
 pub const FLAG_Z: usize = 2; // zero flag
@@ -14,7 +15,7 @@ pub const ALU_ADC: u8 = 3;
 pub const ALU_SUB: u8 = 4;
 pub const ALU_SBB: u8 = 5;
 pub const ALU_MUL: u8 = 6;
-pub const ALU_RES: u8 = 7;
+pub const ALU_DIV: u8 = 7;
 pub const ALU_SHL: u8 = 8;
 pub const ALU_SHR: u8 = 9;
 pub const ALU_ROL: u8 = 10;
@@ -24,8 +25,50 @@ pub const ALU_AND: u8 = 13;
 pub const ALU_IOR: u8 = 14;
 pub const ALU_XOR: u8 = 15;
 
-pub const ALU_MAX_OPCODE: u8 = 15;
-
+// added to an operation to make it double-width
+pub const ALU_WIDE: u8 = 16;
+// added to a divide or multiply to make it signed
+pub const ALU_SIGNED: u8 = 32;
+
+// decimal adjust X after a packed BCD add or subtract
+pub const ALU_DAA: u8 = 64;
+pub const ALU_DAS: u8 = 65;
+
+pub const ALU_MAX_OPCODE: u8 = ALU_DAS;
+
+/// The number of bytes in `ALU::registers`.
+pub const REGISTERS: usize = 8;
+
+/// The arithmetic and logic unit.
+///
+/// Operands are loaded into the X and Y registers, an operation is selected
+/// with `load_op` and `compute` produces `result`, `res_hi` (the high byte of
+/// multiplies and shifts) and the HONZVC flags.
+///
+/// Byte adds and subtracts set H to the carry out of bit 3, or the borrow
+/// from bit 4. `ALU_DAA` and `ALU_DAS` then turn X, the binary sum or difference
+/// of two packed BCD bytes, into their BCD sum or difference using C and H;
+/// C is set on a decimal carry or borrow out of the byte. Other operations
+/// clear H.
+///
+/// `ALU_MUL` plus `ALU_SIGNED` multiplies two's complement numbers; its
+/// flags are those of the 16-bit product, with C and V set if the product
+/// does not fit in a signed byte.
+///
+/// `ALU_DIV` divides X by Y, leaving the quotient in `result` and the
+/// remainder in `res_hi`; plus `ALU_WIDE` it divides `x_hi:x` by Y, and
+/// plus `ALU_SIGNED` it divides two's complement numbers, truncating
+/// towards zero. The flags are those of the quotient, with C set if the
+/// remainder is not zero and V if Y is zero or the quotient does not fit
+/// in a byte. Dividing by zero leaves a quotient of all ones and X as the
+/// remainder.
+///
+/// Other operations plus `ALU_WIDE` work on 16 bits: X and Y are loaded with
+/// `load_x_wide` and `load_y_wide`, `result_wide` is `res_hi` and `result`
+/// together and the flags are those of the 16-bit result. There is no
+/// double-width multiply; a double-width shift or rotate shifts by the
+/// whole of Y.
+#[allow(clippy::upper_case_acronyms)]
 pub struct ALU {
     x: u8,
     y: u8,
@@ -33,8 +76,11 @@ pub struct ALU {
     result: u8,
     res_hi: u8,
     flags: u8,
+    x_hi: u8,
+    y_hi: u8,
 }
 
+/// Creates an ALU with all registers and flags cleared.
 pub fn new() -> ALU {
     ALU {
         x: 0,
@@ -43,10 +89,11 @@ pub fn new() -> ALU {
         result: 0,
         res_hi: 0,
         flags: 0,
+        x_hi: 0,
+        y_hi: 0,
     }
 }
 
-#[allow(dead_code)]
 impl ALU {
     pub fn load_x(&mut self, x: u8) {
         self.x = x;
@@ -56,12 +103,31 @@ impl ALU {
         self.y = y;
     }
 
+    pub fn load_x_wide(&mut self, x: u16) {
+        self.x = (x & 0xFF) as u8;
+        self.x_hi = (x >> 8) as u8;
+    }
+
+    pub fn load_y_wide(&mut self, y: u16) {
+        self.y = (y & 0xFF) as u8;
+        self.y_hi = (y >> 8) as u8;
+    }
+
     pub fn load_op(&mut self, op: u8) {
         self.op = op;
     }
 
     pub fn compute(&mut self) {
-        assert!(self.op < ALU_MAX_OPCODE);
+        assert!(self.op <= ALU_MAX_OPCODE);
+        if self.op & !(ALU_WIDE | ALU_SIGNED) == ALU_DIV {
+            return alu_div(self);
+        }
+        if self.op == ALU_MUL + ALU_SIGNED {
+            return alu_mul_s(self);
+        }
+        if self.op & ALU_WIDE != 0 {
+            return alu_wide(self);
+        }
         match self.op {
             ALU_RST => alu_rst(self),
             ALU_NOP => alu_nop(self),
@@ -70,7 +136,6 @@ impl ALU {
             ALU_SUB => alu_sub(self),
             ALU_SBB => alu_sbb(self),
             ALU_MUL => alu_mul(self),
-            ALU_RES => alu_nop(self),
             ALU_SHL => alu_shl(self),
             ALU_SHR => alu_shr(self),
             ALU_ROL => alu_rol(self),
@@ -79,6 +144,8 @@ impl ALU {
             ALU_AND => alu_and(self),
             ALU_IOR => alu_ior(self),
             ALU_XOR => alu_xor(self),
+            ALU_DAA => alu_daa(self),
+            ALU_DAS => alu_das(self),
 
             _ => alu_nop(self),
         }
@@ -96,10 +163,49 @@ impl ALU {
         self.res_hi
     }
 
+    pub fn result_wide(&self) -> u16 {
+        (self.res_hi as u16) << 8 | self.result as u16
+    }
+
     pub fn flags(&self) -> u8 {
         self.flags
     }
 
+    pub fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
+    }
+
+    /// X, Y, op, result, res_hi, flags and the high bytes of X and Y, in
+    /// that order.
+    pub fn registers(&self) -> [u8; REGISTERS] {
+        [
+            self.x,
+            self.y,
+            self.op,
+            self.result,
+            self.res_hi,
+            self.flags,
+            self.x_hi,
+            self.y_hi,
+        ]
+    }
+
+    pub fn set_registers(&mut self, registers: [u8; REGISTERS]) {
+        let [x, y, op, result, res_hi, flags, x_hi, y_hi] = registers;
+        self.x = x;
+        self.y = y;
+        self.op = op;
+        self.result = result;
+        self.res_hi = res_hi;
+        self.flags = flags;
+        self.x_hi = x_hi;
+        self.y_hi = y_hi;
+    }
+
+    pub fn test_h(&self) -> bool {
+        self.flags.bit(FLAG_H)
+    }
+
     pub fn test_o(&self) -> bool {
         self.flags.bit(FLAG_O)
     }
@@ -129,6 +235,8 @@ fn alu_rst(alu: &mut ALU) {
     alu.result = 0;
     alu.res_hi = 0;
     alu.flags = 0;
+    alu.x_hi = 0;
+    alu.y_hi = 0;
 }
 
 // No operation; result = x
@@ -178,6 +286,11 @@ fn alu_add(alu: &mut ALU) {
         alu.flags.set_bit(FLAG_V, true);
     }
 
+    // calculate half-carry flag
+    if (alu.x & 0x0F) + (alu.y & 0x0F) > 0x0F {
+        alu.flags.set_bit(FLAG_H, true);
+    }
+
     // calculate carry flag
     if carry.bit(8) {
         alu.flags.set_bit(FLAG_C, true);
@@ -190,18 +303,8 @@ fn alu_add(alu: &mut ALU) {
 // Add with carry
 fn alu_adc(alu: &mut ALU) {
     let carry_in = alu.test_c();
-
-    let result: u8 = if carry_in {
-        (Wrapping(alu.x) + Wrapping(alu.y)).0 + 1
-    } else {
-        (Wrapping(alu.x) + Wrapping(alu.y)).0
-    };
-
-    let carry: u16 = if carry_in {
-        alu.x as u16 + alu.y as u16 + 1
-    } else {
-        alu.x as u16 + alu.y as u16
-    };
+    let carry = alu.x as u16 + alu.y as u16 + carry_in as u16;
+    let result = carry as u8;
 
     alu.flags = 0;
     // calculate odd flag
@@ -226,6 +329,11 @@ fn alu_adc(alu: &mut ALU) {
         alu.flags.set_bit(FLAG_V, true);
     }
 
+    // calculate half-carry flag
+    if (alu.x & 0x0F) + (alu.y & 0x0F) + carry_in as u8 > 0x0F {
+        alu.flags.set_bit(FLAG_H, true);
+    }
+
     // calculate carry flag
     if carry.bit(8) {
         alu.flags.set_bit(FLAG_C, true);
@@ -263,6 +371,11 @@ fn alu_sub(alu: &mut ALU) {
         alu.flags.set_bit(FLAG_V, true);
     }
 
+    // calculate half-borrow flag
+    if alu.x & 0x0F < alu.y & 0x0F {
+        alu.flags.set_bit(FLAG_H, true);
+    }
+
     // calculate borrow flag
     if alu.x < alu.y {
         alu.flags.set_bit(FLAG_C, true);
@@ -274,9 +387,9 @@ fn alu_sub(alu: &mut ALU) {
 
 // Subtract with borrow
 fn alu_sbb(alu: &mut ALU) {
-    let borrow = if alu.test_c() { 1 } else { 0 };
+    let borrow = alu.test_c() as u8;
 
-    let result = (Wrapping(alu.x) - Wrapping(alu.y)).0 - borrow;
+    let result = alu.x.wrapping_sub(alu.y).wrapping_sub(borrow);
 
     alu.flags = 0;
 
@@ -302,8 +415,13 @@ fn alu_sbb(alu: &mut ALU) {
         alu.flags.set_bit(FLAG_V, true);
     }
 
+    // calculate half-borrow flag
+    if alu.x & 0x0F < (alu.y & 0x0F) + borrow {
+        alu.flags.set_bit(FLAG_H, true);
+    }
+
     // calculate borrow flag
-    if alu.x < (alu.y + borrow) {
+    if (alu.x as u16) < alu.y as u16 + borrow as u16 {
         alu.flags.set_bit(FLAG_C, true);
     }
 
@@ -335,6 +453,107 @@ fn alu_mul(alu: &mut ALU) {
     alu.res_hi = result_high;
 }
 
+// Decimal adjust after a packed BCD add
+fn alu_daa(alu: &mut ALU) {
+    let mut correction = 0;
+    let mut carry = alu.test_c();
+    if alu.test_h() || alu.x & 0x0F > 9 {
+        correction |= 0x06;
+    }
+    if carry || alu.x > 0x99 {
+        correction |= 0x60;
+        carry = true;
+    }
+    let result = (Wrapping(alu.x) + Wrapping(correction)).0;
+
+    alu.flags = 0;
+    alu.flags
+        .set_bit(FLAG_H, (alu.x & 0x0F) + (correction & 0x0F) > 0x0F);
+    alu.flags.set_bit(FLAG_O, result.bit(0));
+    alu.flags.set_bit(FLAG_N, result.bit(7));
+    alu.flags.set_bit(FLAG_Z, result == 0);
+    alu.flags.set_bit(FLAG_C, carry);
+
+    alu.result = result;
+}
+
+// Decimal adjust after a packed BCD subtract
+fn alu_das(alu: &mut ALU) {
+    let mut correction = 0;
+    let carry = alu.test_c();
+    if alu.test_h() {
+        correction |= 0x06;
+    }
+    if carry {
+        correction |= 0x60;
+    }
+    let result = (Wrapping(alu.x) - Wrapping(correction)).0;
+
+    alu.flags = 0;
+    alu.flags.set_bit(FLAG_H, alu.x & 0x0F < correction & 0x0F);
+    alu.flags.set_bit(FLAG_O, result.bit(0));
+    alu.flags.set_bit(FLAG_N, result.bit(7));
+    alu.flags.set_bit(FLAG_Z, result == 0);
+    alu.flags.set_bit(FLAG_C, carry);
+
+    alu.result = result;
+}
+
+// Signed integer multiply
+fn alu_mul_s(alu: &mut ALU) {
+    let result = alu.x as i8 as i16 * alu.y as i8 as i16;
+    let fits = (-128..=127).contains(&result);
+
+    alu.flags = 0;
+    alu.flags.set_bit(FLAG_O, result & 1 != 0);
+    alu.flags.set_bit(FLAG_N, result < 0);
+    alu.flags.set_bit(FLAG_Z, result == 0);
+    alu.flags.set_bit(FLAG_V, !fits);
+    alu.flags.set_bit(FLAG_C, !fits);
+
+    alu.result = (result & 0xFF) as u8;
+    alu.res_hi = (result >> 8 & 0xFF) as u8;
+}
+
+// Integer divide, of x or x_hi:x by y
+fn alu_div(alu: &mut ALU) {
+    let wide = alu.op & ALU_WIDE != 0;
+    let signed = alu.op & ALU_SIGNED != 0;
+    let x = if wide {
+        (alu.x_hi as u16) << 8 | alu.x as u16
+    } else {
+        alu.x as u16
+    };
+
+    // quotient, remainder and whether the quotient fits in a byte
+    let (quotient, remainder, fits) = if alu.y == 0 {
+        (-1, x as u8 as i32, false)
+    } else if signed {
+        let x = if wide {
+            x as i16 as i32
+        } else {
+            x as u8 as i8 as i32
+        };
+        let y = alu.y as i8 as i32;
+        let quotient = x / y;
+        (quotient, x % y, (-128..=127).contains(&quotient))
+    } else {
+        let quotient = x as i32 / alu.y as i32;
+        (quotient, x as i32 % alu.y as i32, quotient <= 0xFF)
+    };
+    let result = quotient as u8;
+
+    alu.flags = 0;
+    alu.flags.set_bit(FLAG_O, result.bit(0));
+    alu.flags.set_bit(FLAG_N, result.bit(7));
+    alu.flags.set_bit(FLAG_Z, result == 0);
+    alu.flags.set_bit(FLAG_V, !fits);
+    alu.flags.set_bit(FLAG_C, alu.y != 0 && remainder != 0);
+
+    alu.result = result;
+    alu.res_hi = remainder as u8;
+}
+
 // Logical shift left
 fn alu_shl(alu: &mut ALU) {
     let result = (alu.x as u32).checked_shl(alu.y as u32).unwrap_or(0);
@@ -504,3 +723,62 @@ fn alu_xor(alu: &mut ALU) {
         alu.flags.set_bit(FLAG_Z, true);
     }
 }
+
+// Double-width operations on x_hi:x and y_hi:y
+fn alu_wide(alu: &mut ALU) {
+    let x = (alu.x_hi as u16) << 8 | alu.x as u16;
+    let y = (alu.y_hi as u16) << 8 | alu.y as u16;
+    let carry_in = alu.test_c() as u32;
+
+    // the result and the carry (or borrow) and overflow flags
+    let (result, carry, overflow) = match alu.op - ALU_WIDE {
+        ALU_ADD | ALU_ADC => {
+            let carry_in = if alu.op - ALU_WIDE == ALU_ADC {
+                carry_in
+            } else {
+                0
+            };
+            let sum = x as u32 + y as u32 + carry_in;
+            let result = sum as u16;
+            (result, sum > 0xFFFF, (!(x ^ y) & (x ^ result)).bit(15))
+        }
+        ALU_SUB | ALU_SBB => {
+            let borrow = if alu.op - ALU_WIDE == ALU_SBB {
+                carry_in
+            } else {
+                0
+            };
+            let result = x.wrapping_sub(y).wrapping_sub(borrow as u16);
+            (
+                result,
+                (x as u32) < y as u32 + borrow,
+                ((x ^ y) & (x ^ result)).bit(15),
+            )
+        }
+        ALU_SHL => {
+            let shifted = (x as u32).checked_shl(y as u32).unwrap_or(0);
+            (shifted as u16, shifted.bit(16), false)
+        }
+        ALU_SHR => {
+            let shifted = ((x as u32) << 16).checked_shr(y as u32).unwrap_or(0);
+            ((shifted >> 16) as u16, shifted.bit(15), false)
+        }
+        ALU_ROL => (x.rotate_left(y as u32), false, false),
+        ALU_ROR => (x.rotate_right(y as u32), false, false),
+        ALU_NOT => (!x, false, false),
+        ALU_AND => (x & y, false, false),
+        ALU_IOR => (x | y, false, false),
+        ALU_XOR => (x ^ y, false, false),
+        _ => (x, false, false),
+    };
+
+    alu.flags = 0;
+    alu.flags.set_bit(FLAG_O, result.bit(0));
+    alu.flags.set_bit(FLAG_N, result.bit(15));
+    alu.flags.set_bit(FLAG_Z, result == 0);
+    alu.flags.set_bit(FLAG_V, overflow);
+    alu.flags.set_bit(FLAG_C, carry);
+
+    alu.result = (result & 0xFF) as u8;
+    alu.res_hi = (result >> 8) as u8;
+}
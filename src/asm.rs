@@ -0,0 +1,536 @@
+//! Two-pass assembler for stack85 source text.
+//!
+//! Each line holds an optional `label:`, then an instruction or directive,
+//! then an optional `;` comment. Mnemonics are the opcode names from
+//! `control` and are case-insensitive; labels are case-sensitive.
+//!
+//! Operands are numbers (`42`, `0x2A`, `0b101010`, `'*'`), labels, or sums
+//! and differences of these. A leading `<` or `>` selects the low or high
+//! byte of a 16-bit value. Instructions with a 16-bit operand take the
+//! absolute value; IMM_BRANCH and IMM_BRANCH_S given a label take the
+//! offset from the following instruction to that label.
+//!
+//! Directives are `.org addr`, `.byte b, ...`, `.word w, ...` (low byte
+//! first) and `.ascii "text"`.
+
+use crate::control;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// An assembled image, starting at address 0, and its label addresses.
+#[derive(Default)]
+pub struct Assembly {
+    pub image: Vec<u8>,
+    pub labels: HashMap<String, u16>,
+}
+
+impl Assembly {
+    pub fn label(&self, name: &str) -> Option<u16> {
+        self.labels.get(name).copied()
+    }
+
+    /// Finds a label at `addr`, preferring the alphabetically first one.
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.labels_at(addr).first().copied()
+    }
+
+    /// All the labels at `addr`, in alphabetical order.
+    pub fn labels_at(&self, addr: u16) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .labels
+            .iter()
+            .filter(|&(_, &a)| a == addr)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+/// Reads the image at `path`, assembling it first if it is `.s` source;
+/// binary images have no labels. Errors are formatted with the path.
+pub fn load(path: &str) -> Result<Assembly, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    if !path.ends_with(".s") {
+        return Ok(Assembly {
+            image: bytes,
+            labels: HashMap::new(),
+        });
+    }
+
+    assemble(&String::from_utf8_lossy(&bytes)).map_err(|errors| {
+        let lines: Vec<String> = errors
+            .iter()
+            .map(|e| format!("{}:{}: {}", path, e.line, e.message))
+            .collect();
+        lines.join("\n")
+    })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Select {
+    All,
+    Low,
+    High,
+}
+
+enum Term {
+    Number(i64),
+    Label(String),
+}
+
+struct Expr {
+    select: Select,
+    terms: Vec<(bool, Term)>, // (negated, term)
+}
+
+enum Item {
+    Instruction(u8, Option<Expr>),
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+    Ascii(Vec<u8>),
+}
+
+struct Line {
+    number: usize,
+    addr: u16,
+    item: Item,
+}
+
+fn error(line: usize, message: String) -> AsmError {
+    AsmError { line, message }
+}
+
+/// Assembles `source`, returning every error found if it does not assemble.
+pub fn assemble(source: &str) -> Result<Assembly, Vec<AsmError>> {
+    let mut errors = Vec::new();
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut lines = Vec::new();
+    let mut addr: u32 = 0;
+
+    // pass 1: parse, assign addresses and collect labels
+    for (index, text) in source.lines().enumerate() {
+        let number = index + 1;
+        let mut rest = strip_comment(text).trim();
+
+        if let Some((label, after)) = split_label(rest) {
+            if labels.contains_key(label) {
+                errors.push(error(number, format!("duplicate label `{}`", label)));
+            } else if addr > 0xFFFF {
+                errors.push(error(number, "address exceeds 0xFFFF".to_string()));
+            } else {
+                labels.insert(label.to_string(), addr as u16);
+            }
+            rest = after.trim();
+        }
+
+        if rest.is_empty() {
+            continue;
+        }
+
+        let (word, args) = match rest.find(char::is_whitespace) {
+            Some(i) => (&rest[..i], rest[i..].trim()),
+            None => (rest, ""),
+        };
+
+        if word.eq_ignore_ascii_case(".org") {
+            match parse_expr(args).and_then(|e| eval(&e, &labels)) {
+                Ok(target) if target < addr as i64 => errors.push(error(
+                    number,
+                    format!(".org {:#06X} is behind the current address", target),
+                )),
+                Ok(target) if target > 0xFFFF => errors.push(error(
+                    number,
+                    format!(".org {:#X} is beyond 0xFFFF", target),
+                )),
+                Ok(target) => addr = target as u32,
+                Err(message) => errors.push(error(number, message)),
+            }
+            continue;
+        }
+
+        let item = match parse_item(word, args) {
+            Ok(item) => item,
+            Err(message) => {
+                errors.push(error(number, message));
+                continue;
+            }
+        };
+
+        let size = match &item {
+            Item::Instruction(op, _) => control::instruction_length(*op) as u32,
+            Item::Bytes(exprs) => exprs.len() as u32,
+            Item::Words(exprs) => 2 * exprs.len() as u32,
+            Item::Ascii(bytes) => bytes.len() as u32,
+        };
+
+        if addr + size > 0x10000 {
+            errors.push(error(number, "program exceeds 64 KiB".to_string()));
+            break;
+        }
+
+        lines.push(Line {
+            number,
+            addr: addr as u16,
+            item,
+        });
+        addr += size;
+    }
+
+    // pass 2: resolve operands and emit bytes
+    let mut image = vec![0; addr as usize];
+    for line in &lines {
+        match emit(line, &labels) {
+            Ok(bytes) => {
+                let start = line.addr as usize;
+                image[start..start + bytes.len()].copy_from_slice(&bytes);
+            }
+            Err(message) => errors.push(error(line.number, message)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(Assembly { image, labels })
+    } else {
+        errors.sort_by_key(|e| e.line);
+        Err(errors)
+    }
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' => return &text[..i],
+            None => {}
+        }
+    }
+    text
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let colon = text.find(':')?;
+    let label = &text[..colon];
+    let mut chars = label.chars();
+
+    if chars.next().is_some_and(is_ident_start) && chars.all(is_ident) {
+        Some((label, &text[colon + 1..]))
+    } else {
+        None
+    }
+}
+
+fn parse_item(word: &str, args: &str) -> Result<Item, String> {
+    if let Some(directive) = word.strip_prefix('.') {
+        return match directive.to_ascii_lowercase().as_str() {
+            "byte" => Ok(Item::Bytes(parse_list(args)?)),
+            "word" => Ok(Item::Words(parse_list(args)?)),
+            "ascii" => Ok(Item::Ascii(parse_string(args)?)),
+            _ => Err(format!("unknown directive `{}`", word)),
+        };
+    }
+
+    let op = control::opcode(word).ok_or_else(|| format!("unknown mnemonic `{}`", word))?;
+    if !control::takes_operand(op) {
+        if !args.is_empty() {
+            return Err(format!("{} takes no operand", word.to_ascii_uppercase()));
+        }
+        Ok(Item::Instruction(op, None))
+    } else if args.is_empty() {
+        Err(format!("{} needs an operand", word.to_ascii_uppercase()))
+    } else {
+        Ok(Item::Instruction(op, Some(parse_expr(args)?)))
+    }
+}
+
+fn parse_list(args: &str) -> Result<Vec<Expr>, String> {
+    if args.is_empty() {
+        return Err("expected at least one value".to_string());
+    }
+    split_list(args).into_iter().map(parse_expr).collect()
+}
+
+// splits on commas that are not inside character literals
+fn split_list(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in args.char_indices() {
+        if quoted {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '\'' {
+                quoted = false;
+            }
+        } else if c == '\'' {
+            quoted = true;
+        } else if c == ',' {
+            parts.push(&args[start..i]);
+            start = i + 1;
+        }
+    }
+    parts.push(&args[start..]);
+    parts
+}
+
+fn parse_string(args: &str) -> Result<Vec<u8>, String> {
+    let inner = args
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .filter(|_| args.len() >= 2)
+        .ok_or_else(|| "expected a quoted string".to_string())?;
+
+    unescape(inner)
+}
+
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                Some('\'') => '\'',
+                Some(other) => return Err(format!("unknown escape `\\{}`", other)),
+                None => return Err("unfinished escape".to_string()),
+            }
+        } else {
+            c
+        };
+
+        if !c.is_ascii() {
+            return Err(format!("`{}` is not ASCII", c));
+        }
+        bytes.push(c as u8);
+    }
+    Ok(bytes)
+}
+
+fn parse_expr(text: &str) -> Result<Expr, String> {
+    let text = text.trim();
+    let (select, mut rest) = if let Some(rest) = text.strip_prefix('<') {
+        (Select::Low, rest.trim_start())
+    } else if let Some(rest) = text.strip_prefix('>') {
+        (Select::High, rest.trim_start())
+    } else {
+        (Select::All, text)
+    };
+
+    let mut terms = Vec::new();
+    let mut negated = false;
+
+    loop {
+        if let Some(after) = rest.strip_prefix('-') {
+            negated = !negated;
+            rest = after.trim_start();
+        }
+
+        let (term, after) = parse_term(rest)?;
+        terms.push((negated, term));
+        rest = after.trim_start();
+
+        if rest.is_empty() {
+            break;
+        } else if let Some(after) = rest.strip_prefix('+') {
+            negated = false;
+            rest = after.trim_start();
+        } else if let Some(after) = rest.strip_prefix('-') {
+            negated = true;
+            rest = after.trim_start();
+        } else {
+            return Err(format!("unexpected `{}`", rest));
+        }
+    }
+
+    Ok(Expr { select, terms })
+}
+
+fn parse_term(text: &str) -> Result<(Term, &str), String> {
+    if let Some(after) = text.strip_prefix('\'') {
+        let end = if after.starts_with('\\') {
+            after.char_indices().nth(2).map(|(i, _)| i)
+        } else {
+            after.char_indices().nth(1).map(|(i, _)| i)
+        };
+
+        return match end {
+            Some(end) if after[end..].starts_with('\'') => {
+                let bytes = unescape(&after[..end])?;
+                Ok((Term::Number(bytes[0] as i64), &after[end + 1..]))
+            }
+            _ => Err("bad character literal".to_string()),
+        };
+    }
+
+    let end = text.find(|c| !is_ident(c)).unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+
+    if word.is_empty() {
+        return Err(if text.is_empty() {
+            "expected a value".to_string()
+        } else {
+            format!("unexpected `{}`", text)
+        });
+    }
+
+    if word.starts_with(|c: char| c.is_ascii_digit()) {
+        let lower = word.to_ascii_lowercase();
+        let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16)
+        } else if let Some(bin) = lower.strip_prefix("0b") {
+            i64::from_str_radix(bin, 2)
+        } else {
+            lower.parse()
+        };
+
+        match parsed {
+            Ok(n) => Ok((Term::Number(n), rest)),
+            Err(_) => Err(format!("bad number `{}`", word)),
+        }
+    } else if word.starts_with(is_ident_start) {
+        Ok((Term::Label(word.to_string()), rest))
+    } else {
+        Err(format!("unexpected `{}`", text))
+    }
+}
+
+fn eval(expr: &Expr, labels: &HashMap<String, u16>) -> Result<i64, String> {
+    let mut value: i64 = 0;
+
+    for (negated, term) in &expr.terms {
+        let x = match term {
+            Term::Number(n) => *n,
+            Term::Label(name) => match labels.get(name) {
+                Some(&addr) => addr as i64,
+                None => return Err(format!("undefined label `{}`", name)),
+            },
+        };
+        let sum = if *negated {
+            value.checked_sub(x)
+        } else {
+            value.checked_add(x)
+        };
+        value = sum.ok_or_else(|| "expression overflows".to_string())?;
+    }
+
+    Ok(match expr.select {
+        Select::All => value,
+        Select::Low => value & 0xFF,
+        Select::High => value >> 8 & 0xFF,
+    })
+}
+
+fn has_label(expr: &Expr) -> bool {
+    expr.terms
+        .iter()
+        .any(|(_, term)| matches!(term, Term::Label(_)))
+}
+
+fn byte(value: i64) -> Result<u8, String> {
+    if (-128..=255).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("{} does not fit in a byte", value))
+    }
+}
+
+fn word(value: i64) -> Result<[u8; 2], String> {
+    if (-32768..=65535).contains(&value) {
+        Ok((value as u16).to_le_bytes())
+    } else {
+        Err(format!("{} does not fit in a word", value))
+    }
+}
+
+fn emit(line: &Line, labels: &HashMap<String, u16>) -> Result<Vec<u8>, String> {
+    match &line.item {
+        Item::Instruction(op, None) => {
+            // any bytes after the opcode are 0
+            let mut bytes = vec![0; control::instruction_length(*op) as usize];
+            bytes[0] = *op;
+            Ok(bytes)
+        }
+        Item::Instruction(op, Some(expr)) => {
+            let value = eval(expr, labels)?;
+            let next = line.addr as i64 + control::instruction_length(*op) as i64;
+            let relative = has_label(expr) && expr.select == Select::All;
+
+            match *op {
+                control::IMM_BRANCH if relative => {
+                    let offset = value - next;
+                    if !(0..=255).contains(&offset) {
+                        return Err(format!("branch target is {} bytes away", offset));
+                    }
+                    Ok(vec![*op, offset as u8])
+                }
+                control::IMM_BRANCH_S if relative => {
+                    let offset = value - next;
+                    if !(-128..=127).contains(&offset) {
+                        return Err(format!("branch target is {} bytes away", offset));
+                    }
+                    Ok(vec![*op, offset as u8])
+                }
+                _ if control::instruction_length(*op) == 2 => Ok(vec![*op, byte(value)?]),
+                _ => {
+                    let [low, high] = word(value)?;
+                    Ok(vec![*op, low, high])
+                }
+            }
+        }
+        Item::Bytes(exprs) => exprs.iter().map(|e| byte(eval(e, labels)?)).collect(),
+        Item::Words(exprs) => {
+            let mut bytes = Vec::new();
+            for e in exprs {
+                bytes.extend_from_slice(&word(eval(e, labels)?)?);
+            }
+            Ok(bytes)
+        }
+        Item::Ascii(bytes) => Ok(bytes.clone()),
+    }
+}
@@ -0,0 +1,56 @@
+use stack85::asm;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("usage: stack85-as <source> [-o <image>]");
+    process::exit(2);
+}
+
+fn main() {
+    let mut input = None;
+    let mut output = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => usage(),
+            _ if input.is_none() => input = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let input = input.unwrap_or_else(|| usage());
+    let output = output.unwrap_or_else(|| {
+        Path::new(&input)
+            .with_extension("bin")
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let source = match fs::read_to_string(&input) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", input, e);
+            process::exit(1);
+        }
+    };
+
+    let assembly = match asm::assemble(&source) {
+        Ok(assembly) => assembly,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("{}:{}: {}", input, e.line, e.message);
+            }
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = fs::write(&output, &assembly.image) {
+        eprintln!("{}: {}", output, e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,104 @@
+use stack85::{asm, config, debugger};
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: stack85-dbg <image | source.s> [--in <file>] [--out <file>] \
+         [--machine <file>] [--mem <bytes>] [--base <addr>] [--console <addr>] \
+         [--irq <line>] [--history <steps>]"
+    );
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn main() {
+    let mut setup = config::setup();
+    let mut history = debugger::DEFAULT_HISTORY;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match setup.option(&arg, &mut args) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("{}", e);
+                usage();
+            }
+        }
+
+        match arg.as_str() {
+            "--history" => {
+                let n = args.next().unwrap_or_else(|| usage());
+                history = n.parse().unwrap_or_else(|_| {
+                    eprintln!("bad number `{}`", n);
+                    process::exit(2);
+                });
+            }
+            "-h" | "--help" => usage(),
+            _ if setup.input.is_none() => setup.input = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let config = setup.config().unwrap_or_else(|e| fail(&e));
+
+    // the machine description may bring the program instead
+    let assembly = match &setup.input {
+        Some(input) => asm::load(input).unwrap_or_else(|e| fail(&e)),
+        None if !config.images.is_empty() => asm::Assembly::default(),
+        None => usage(),
+    };
+
+    // the debugger's own commands come from stdin, so the console reads
+    // nothing unless given a file
+    setup.console_in.get_or_insert_with(|| {
+        if cfg!(windows) {
+            "NUL".to_string()
+        } else {
+            "/dev/null".to_string()
+        }
+    });
+
+    let mut control = setup
+        .build(&config, &assembly.image)
+        .unwrap_or_else(|e| fail(&e));
+    control.set_history(history);
+
+    let mut debugger = debugger::new(control, assembly);
+    println!("{}", debugger.command("dis").unwrap_or_default());
+
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(dbg) ");
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+
+        // an empty line repeats the previous command
+        let line = match line.trim() {
+            "" => last.clone(),
+            line => line.to_string(),
+        };
+        if line == "q" || line == "quit" {
+            break;
+        }
+
+        match debugger.command(&line) {
+            Ok(text) if text.is_empty() => {}
+            Ok(text) => println!("{}", text),
+            Err(message) => println!("error: {}", message),
+        }
+        last = line;
+    }
+}
@@ -0,0 +1,59 @@
+use stack85::{asm, disasm};
+use std::env;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("usage: stack85-dis <image | source.s> [--from <addr>] [--to <addr>]");
+    process::exit(2);
+}
+
+fn parse_addr(text: &str) -> u32 {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+
+    match parsed {
+        Ok(addr) if addr <= 0x10000 => addr,
+        _ => {
+            eprintln!("bad address `{}`", text);
+            process::exit(2);
+        }
+    }
+}
+
+fn main() {
+    let mut input = None;
+    let mut from = 0;
+    let mut to = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => from = parse_addr(&args.next().unwrap_or_else(|| usage())),
+            "--to" => to = Some(parse_addr(&args.next().unwrap_or_else(|| usage()))),
+            "-h" | "--help" => usage(),
+            _ if input.is_none() => input = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    // assembler sources are assembled first so their labels can be shown
+    let input = input.unwrap_or_else(|| usage());
+    let assembly = asm::load(&input).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    let to = to.unwrap_or(assembly.image.len() as u32);
+    if from > 0xFFFF {
+        usage();
+    }
+
+    for instruction in disasm::disassemble(&assembly.image, from as u16, to) {
+        for name in assembly.labels_at(instruction.addr) {
+            println!("{}:", name);
+        }
+        println!("{}", instruction.listing(&assembly));
+    }
+}
@@ -0,0 +1,150 @@
+use stack85::{asm, config, debugger, gdb, savestate, trace};
+use std::env;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: stack85-run <image | source.s> [--in <file>] [--out <file>] \
+         [--machine <file>] [--mem <bytes>] [--base <addr>] [--console <addr>] \
+         [--irq <line>] [--regs] [--gdb <port>] \
+         [--trace <file>] [--load-state <file>] [--save-state <file>] \
+         [--limit <n>]"
+    );
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn main() {
+    let mut setup = config::setup();
+    let mut regs = false;
+    let mut gdb_port = None;
+    let mut trace_path = None;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut limit: Option<u64> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match setup.option(&arg, &mut args) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("{}", e);
+                usage();
+            }
+        }
+
+        match arg.as_str() {
+            "--regs" => regs = true,
+            "--trace" => trace_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--load-state" => load_state = Some(args.next().unwrap_or_else(|| usage())),
+            "--save-state" => save_state = Some(args.next().unwrap_or_else(|| usage())),
+            "--limit" => {
+                let n = args.next().unwrap_or_else(|| usage());
+                limit = Some(n.parse().unwrap_or_else(|_| {
+                    eprintln!("bad number `{}`", n);
+                    process::exit(2);
+                }));
+            }
+            "--gdb" => {
+                let port = args.next().unwrap_or_else(|| usage());
+                gdb_port = Some(config::address(&port).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    usage();
+                }));
+            }
+            "-h" | "--help" => usage(),
+            _ if setup.input.is_none() => setup.input = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let config = setup.config().unwrap_or_else(|e| fail(&e));
+
+    // a save state or the machine description brings its own memory, so
+    // the image is optional
+    let image = match &setup.input {
+        Some(input) => asm::load(input).unwrap_or_else(|e| fail(&e)).image,
+        None if load_state.is_some() || !config.images.is_empty() => Vec::new(),
+        None => usage(),
+    };
+
+    let mut control = setup.build(&config, &image).unwrap_or_else(|e| fail(&e));
+    if let Some(path) = &trace_path {
+        let tracer = trace::create(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+        control.set_tracer(Box::new(tracer));
+    }
+    if let Some(path) = &load_state {
+        savestate::load(&mut control, path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+    }
+
+    if let Some(port) = gdb_port {
+        control.set_history(debugger::DEFAULT_HISTORY);
+        eprintln!("waiting for GDB on localhost:{}", port);
+        control = gdb::serve(control, ("127.0.0.1", port)).unwrap_or_else(|e| {
+            eprintln!("gdb: {}", e);
+            process::exit(1);
+        });
+    }
+
+    let mut remaining = limit;
+    while gdb_port.is_none() {
+        match &mut remaining {
+            Some(n) => {
+                while *n > 0 && control.is_running() {
+                    control.step();
+                    *n -= 1;
+                }
+                if *n == 0 {
+                    break;
+                }
+            }
+            None => control.run(),
+        }
+
+        // keep the devices ticking until one of them wakes the machine;
+        // each tick counts against the limit
+        if control.is_waiting() && control.int_mask() != 0 {
+            if let Some(n) = &mut remaining {
+                *n -= 1;
+            }
+            thread::sleep(Duration::from_millis(1));
+            control.tick();
+        } else {
+            break;
+        }
+    }
+
+    if let Some(mut tracer) = control.take_tracer() {
+        if let Err(e) = tracer.finish() {
+            eprintln!("{}: {}", trace_path.unwrap_or_default(), e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(path) = &save_state {
+        savestate::save(&control, path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+    }
+
+    if regs || control.fault().is_some() {
+        control.view();
+    }
+    if control.fault().is_some() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,76 @@
+use stack85::{asm, trace};
+use std::env;
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("usage: stack85-trace dump <trace> [--labels <source.s>]");
+    eprintln!("       stack85-trace diff <trace> <trace> [--labels <source.s>]");
+    process::exit(2);
+}
+
+fn fail(path: &str, e: io::Error) -> ! {
+    eprintln!("{}: {}", path, e);
+    process::exit(2);
+}
+
+fn open(path: &str) -> trace::Reader<io::BufReader<std::fs::File>> {
+    trace::open(path).unwrap_or_else(|e| fail(path, e))
+}
+
+fn main() {
+    let mut files = Vec::new();
+    let mut assembly = asm::Assembly::default();
+
+    let mut args = env::args().skip(1);
+    let command = args.next().unwrap_or_else(|| usage());
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--labels" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                assembly = asm::load(&path).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    process::exit(2);
+                });
+            }
+            "-h" | "--help" => usage(),
+            _ => files.push(arg),
+        }
+    }
+
+    match (command.as_str(), files.as_slice()) {
+        ("dump", [path]) => {
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            for (i, step) in open(path).enumerate() {
+                let step = step.unwrap_or_else(|e| fail(path, e));
+                if writeln!(out, "{:8}  {}", i, step.text(&assembly)).is_err() {
+                    return;
+                }
+            }
+        }
+        ("diff", [left, right]) => {
+            let divergence = trace::diff(open(left), open(right))
+                .unwrap_or_else(|e| fail(&format!("{} or {}", left, right), e));
+
+            let divergence = match divergence {
+                Some(divergence) => divergence,
+                None => return,
+            };
+
+            println!(
+                "traces diverge at step {} ({})",
+                divergence.index,
+                divergence.fields().join(", ")
+            );
+            for (path, step) in [(left, &divergence.left), (right, &divergence.right)] {
+                match step {
+                    Some(step) => println!("{}: {}", path, step.text(&assembly)),
+                    None => println!("{}: end of trace", path),
+                }
+            }
+            process::exit(1);
+        }
+        _ => usage(),
+    }
+}
@@ -0,0 +1,461 @@
+//! Machine configuration, and the machine description files it can be read
+//! from.
+//!
+//! A description file has one setting per line; `;` starts a comment and
+//! numbers are decimal or `0x` hexadecimal:
+//!
+//! ```text
+//! memory 0x10000          ; bytes of memory from address 0
+//! rom 0x0000 0x2000 boot.s ; start, size and optional image
+//! ram 0x2000 0xE000       ; start and size
+//! rom_writes ignore       ; or fault, the default
+//! image data.bin 0x4000   ; an image to load, at 0 unless given
+//! banks 0x8000 0x4000 16 0xFFF0 ; window start and size, banks, register
+//! bank 3 table.bin        ; an image to load into a bank, at the window
+//!                         ; start unless given
+//! stack 0x1000 0x1FFF     ; initial SP and stack base, and stack limit
+//! calls stack             ; CALL pushes the return address; or link, the
+//!                         ; default
+//! ```
+//!
+//! Without any `rom` or `ram` line all of memory is RAM; with them, the
+//! addresses they leave out are holes that fault. The bank window must not
+//! overlap ROM. Image paths are relative to the description file.
+
+use crate::asm;
+use crate::console;
+use crate::control::{self, CallMode, Control};
+use crate::memory::{self, Banking, Region, RegionKind, RomWrites};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// How to build a machine; see `control::from_config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Bytes of memory from address 0; accesses beyond them fault. At most
+    /// `memory::ADDRESS_SPACE`.
+    pub mem_size: usize,
+    pub regions: Vec<Region>,
+    pub rom_writes: RomWrites,
+    pub banking: Option<Banking>,
+    /// The stack base, which SP starts at, and the stack limit; see
+    /// `Control::stack_limit`.
+    pub stack: Option<(u16, u16)>,
+    pub call_mode: CallMode,
+    /// Images to load into a new machine with `load_images`.
+    pub images: Vec<Image>,
+}
+
+/// An image file, raw or assembler source, and the address to load it at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub path: String,
+    pub base: u16,
+    /// The bank to load the image into, if not the memory itself.
+    pub bank: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The default configuration: memory fills the whole address space and is
+/// all RAM.
+pub fn new() -> Config {
+    Config {
+        mem_size: memory::ADDRESS_SPACE,
+        regions: Vec::new(),
+        rom_writes: RomWrites::Fault,
+        banking: None,
+        stack: None,
+        call_mode: CallMode::Link,
+        images: Vec::new(),
+    }
+}
+
+/// Reads the machine description file at `path`.
+pub fn load(path: &str) -> Result<Config, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut config = parse(&text).map_err(|e| format!("{}:{}: {}", path, e.line, e.message))?;
+
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    for image in &mut config.images {
+        image.path = dir.join(&image.path).to_string_lossy().into_owned();
+    }
+    Ok(config)
+}
+
+/// Parses a machine description.
+pub fn parse(text: &str) -> Result<Config, ConfigError> {
+    let mut config = new();
+    // the line each region came from, for errors found at the end
+    let mut region_lines = Vec::new();
+    let mut banks_line = 0;
+    let mut bank_image_lines = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let error = |message: String| ConfigError {
+            line: line_number,
+            message,
+        };
+
+        let line = line.split(';').next().unwrap_or("");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (setting, args) = match words.split_first() {
+            Some((setting, args)) => (*setting, args),
+            None => continue,
+        };
+
+        match (setting, args) {
+            ("memory", [size]) => {
+                config.mem_size = number(size).map_err(error)?;
+                if config.mem_size > memory::ADDRESS_SPACE {
+                    return Err(error(format!(
+                        "memory is larger than the {} byte address space",
+                        memory::ADDRESS_SPACE
+                    )));
+                }
+            }
+            ("ram", [start, size]) | ("rom", [start, size]) | ("rom", [start, size, _]) => {
+                let start = number(start).map_err(error)?;
+                let size = number(size).map_err(error)?;
+                if size == 0 || start + size > memory::ADDRESS_SPACE {
+                    return Err(error(
+                        "region is empty or reaches past the address space".to_string(),
+                    ));
+                }
+
+                let kind = if setting == "rom" {
+                    RegionKind::Rom
+                } else {
+                    RegionKind::Ram
+                };
+                config.regions.push(Region {
+                    start: start as u16,
+                    end: (start + size - 1) as u16,
+                    kind,
+                });
+                region_lines.push(line_number);
+
+                if let [_, _, path] = args {
+                    config.images.push(Image {
+                        path: path.to_string(),
+                        base: start as u16,
+                        bank: None,
+                    });
+                }
+            }
+            ("rom_writes", ["fault"]) => config.rom_writes = RomWrites::Fault,
+            ("rom_writes", ["ignore"]) => config.rom_writes = RomWrites::Ignore,
+            ("image", [path]) | ("image", [path, _]) => {
+                let base = match args.get(1) {
+                    Some(base) => number(base).map_err(error)?,
+                    None => 0,
+                };
+                if base >= memory::ADDRESS_SPACE {
+                    return Err(error(format!("{:#X} is outside the address space", base)));
+                }
+                config.images.push(Image {
+                    path: path.to_string(),
+                    base: base as u16,
+                    bank: None,
+                });
+            }
+            ("banks", [start, size, count, register]) => {
+                if config.banking.is_some() {
+                    return Err(error("banks are already set".to_string()));
+                }
+                let start = number(start).map_err(error)?;
+                let size = number(size).map_err(error)?;
+                let count = number(count).map_err(error)?;
+                let register = number(register).map_err(error)?;
+                if size == 0 || start + size > memory::ADDRESS_SPACE {
+                    return Err(error(
+                        "bank window is empty or reaches past the address space".to_string(),
+                    ));
+                }
+                if count == 0 || count > 256 {
+                    return Err(error("there must be 1 to 256 banks".to_string()));
+                }
+                if register >= memory::ADDRESS_SPACE {
+                    return Err(error(format!(
+                        "{:#X} is outside the address space",
+                        register
+                    )));
+                }
+                if (start..start + size).contains(&register) {
+                    return Err(error("bank register is inside the window".to_string()));
+                }
+                config.banking = Some(Banking {
+                    start: start as u16,
+                    end: (start + size - 1) as u16,
+                    register: register as u16,
+                    count,
+                });
+                banks_line = line_number;
+            }
+            ("bank", [bank, path]) | ("bank", [bank, path, _]) => {
+                let bank = number(bank).map_err(error)?;
+                let base = match args.get(2) {
+                    Some(base) => Some(number(base).map_err(error)?),
+                    None => None,
+                };
+                if bank > 0xFF || base.is_some_and(|b| b >= memory::ADDRESS_SPACE) {
+                    return Err(error("bank or address out of range".to_string()));
+                }
+                config.images.push(Image {
+                    path: path.to_string(),
+                    // the window start, once known
+                    base: base.unwrap_or(0) as u16,
+                    bank: Some(bank as u8),
+                });
+                bank_image_lines.push((config.images.len() - 1, base.is_none(), line_number));
+            }
+            ("stack", [base, limit]) => {
+                let base = number(base).map_err(error)?;
+                let limit = number(limit).map_err(error)?;
+                if limit >= memory::ADDRESS_SPACE || base > limit {
+                    return Err(error(
+                        "stack limit is below the base or outside the address space".to_string(),
+                    ));
+                }
+                config.stack = Some((base as u16, limit as u16));
+            }
+            ("calls", ["link"]) => config.call_mode = CallMode::Link,
+            ("calls", ["stack"]) => config.call_mode = CallMode::Stack,
+            ("memory", _)
+            | ("ram", _)
+            | ("rom", _)
+            | ("rom_writes", _)
+            | ("image", _)
+            | ("banks", _)
+            | ("bank", _)
+            | ("stack", _)
+            | ("calls", _) => return Err(error(format!("bad arguments to `{}`", setting))),
+            _ => return Err(error(format!("unknown setting `{}`", setting))),
+        }
+    }
+
+    for (i, (region, &line)) in config.regions.iter().zip(&region_lines).enumerate() {
+        let error = |message: &str| ConfigError {
+            line,
+            message: message.to_string(),
+        };
+        if region.end as usize >= config.mem_size {
+            return Err(error("region reaches past the end of memory"));
+        }
+        if config.regions[..i]
+            .iter()
+            .any(|r| region.end >= r.start && region.start <= r.end)
+        {
+            return Err(error("region overlaps another region"));
+        }
+    }
+
+    if let Some(banking) = config.banking {
+        let error = |message: &str| ConfigError {
+            line: banks_line,
+            message: message.to_string(),
+        };
+        if banking.end as usize >= config.mem_size {
+            return Err(error("bank window reaches past the end of memory"));
+        }
+        if config
+            .regions
+            .iter()
+            .any(|r| r.kind == RegionKind::Rom && banking.end >= r.start && banking.start <= r.end)
+        {
+            return Err(error("bank window overlaps ROM"));
+        }
+    }
+    for (i, default_base, line) in bank_image_lines {
+        let image = &mut config.images[i];
+        match config.banking {
+            Some(banking) if (image.bank.unwrap_or(0) as usize) < banking.count => {
+                if default_base {
+                    image.base = banking.start;
+                }
+            }
+            _ => {
+                return Err(ConfigError {
+                    line,
+                    message: "no such bank".to_string(),
+                })
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+/// Parses a decimal or `0x` hexadecimal number.
+pub fn number(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("bad number `{}`", text))
+}
+
+/// Parses a number that must be an address.
+pub fn address(text: &str) -> Result<u16, String> {
+    match number(text)? {
+        n if n < memory::ADDRESS_SPACE => Ok(n as u16),
+        _ => Err(format!("`{}` is outside the address space", text)),
+    }
+}
+
+impl Config {
+    /// Loads the configured images into `control`, which should have been
+    /// built from this configuration.
+    pub fn load_images(&self, control: &mut Control) -> Result<(), String> {
+        for image in &self.images {
+            let assembly = asm::load(&image.path)?;
+            match image.bank {
+                Some(bank) => control.load_bank(bank, image.base, &assembly.image),
+                None => control.load_image(image.base, &assembly.image),
+            }
+            .map_err(|e| format!("{}: {}", image.path, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// The options the command-line tools share for building a machine: the
+/// program, the machine description and the console.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setup {
+    /// The image or assembler source to load at `base`.
+    pub input: Option<String>,
+    pub machine: Option<String>,
+    /// Overrides the machine description's memory size.
+    pub mem_size: Option<usize>,
+    pub base: u16,
+    /// Console input and output files; `None` selects stdin or stdout.
+    pub console_in: Option<String>,
+    pub console_out: Option<String>,
+    pub console_base: u16,
+    pub irq_line: u8,
+}
+
+/// The default setup: no program, no machine description and the console
+/// on stdin and stdout at `console::DEFAULT_BASE`.
+pub fn setup() -> Setup {
+    Setup {
+        input: None,
+        machine: None,
+        mem_size: None,
+        base: 0,
+        console_in: None,
+        console_out: None,
+        console_base: console::DEFAULT_BASE,
+        irq_line: 0,
+    }
+}
+
+impl Setup {
+    /// Applies `arg` if it is one of the shared options, taking its value
+    /// from `args`, and returns whether it was.
+    pub fn option(
+        &mut self,
+        arg: &str,
+        args: &mut dyn Iterator<Item = String>,
+    ) -> Result<bool, String> {
+        let mut value = || args.next().ok_or(format!("`{}` needs a value", arg));
+        match arg {
+            "--in" => self.console_in = Some(value()?),
+            "--out" => self.console_out = Some(value()?),
+            "--machine" => self.machine = Some(value()?),
+            "--mem" => {
+                let size = number(&value()?)?;
+                if size > memory::ADDRESS_SPACE {
+                    return Err(format!(
+                        "memory is larger than the {} byte address space",
+                        memory::ADDRESS_SPACE
+                    ));
+                }
+                self.mem_size = Some(size);
+            }
+            "--base" => self.base = address(&value()?)?,
+            "--console" => self.console_base = address(&value()?)?,
+            "--irq" => {
+                let line = number(&value()?)?;
+                if line >= control::IRQ_LINES as usize {
+                    return Err(format!("there are only {} irq lines", control::IRQ_LINES));
+                }
+                self.irq_line = line as u8;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Reads the machine description, if there is one, and applies the
+    /// memory size override.
+    pub fn config(&self) -> Result<Config, String> {
+        let mut config = match &self.machine {
+            Some(path) => load(path)?,
+            None => new(),
+        };
+        if let Some(size) = self.mem_size {
+            config.mem_size = size;
+        }
+
+        if config
+            .regions
+            .iter()
+            .any(|r| r.end as usize >= config.mem_size)
+        {
+            return Err(format!(
+                "memory regions reach past {} bytes of memory",
+                config.mem_size
+            ));
+        }
+        if config
+            .banking
+            .is_some_and(|b| b.end as usize >= config.mem_size)
+        {
+            return Err(format!(
+                "the bank window reaches past {} bytes of memory",
+                config.mem_size
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Builds a started machine from `config`: its images, then `image` at
+    /// the base, with IP at the base and the console mapped.
+    pub fn build(&self, config: &Config, image: &[u8]) -> Result<Control, String> {
+        let console = console::files(
+            self.console_in.as_deref(),
+            self.console_out.as_deref(),
+            self.irq_line,
+        )
+        .map_err(|e| format!("console: {}", e))?;
+
+        let mut control = control::from_config(config);
+        config.load_images(&mut control)?;
+        control
+            .load_image(self.base, image)
+            .map_err(|e| format!("{}: {}", self.input.as_deref().unwrap_or_default(), e))?;
+        control.set_instr_ptr(self.base);
+        control
+            .memory_mut()
+            .map_device(self.console_base, console::SIZE, Box::new(console))
+            .map_err(|e| format!("console: {}", e))?;
+        control.start();
+        Ok(control)
+    }
+}
@@ -0,0 +1,144 @@
+//! A character console mapped as a status/data register pair.
+//!
+//! Output is written to the host as soon as the program stores to the data
+//! register. Input is read on a background thread, so loading the data
+//! register never blocks; the program polls the status register or enables
+//! the receive interrupt.
+
+use crate::control;
+use crate::device::Device;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// the register pair sits where the test driver's text buffer lives
+pub const DEFAULT_BASE: u16 = 175;
+pub const SIZE: u16 = 2;
+
+pub const STATUS: u16 = 0;
+pub const DATA: u16 = 1;
+
+pub const STATUS_RX_READY: u8 = 0b00000001; // a character can be read
+pub const STATUS_TX_READY: u8 = 0b00000010; // always set
+pub const STATUS_RX_CLOSED: u8 = 0b00000100; // input ended, nothing left
+pub const STATUS_RX_IRQ: u8 = 0b10000000; // interrupt while RX_READY or CLOSED
+
+pub struct Console {
+    input: Receiver<u8>,
+    next: Option<u8>,
+    closed: bool,
+    output: Box<dyn Write + Send>,
+    irq_enabled: bool,
+    irq_line: u8,
+}
+
+/// Creates a console reading from `input` and writing to `output`, raising
+/// interrupt line `irq_line` when enabled.
+pub fn new(input: Box<dyn Read + Send>, output: Box<dyn Write + Send>, irq_line: u8) -> Console {
+    assert!(irq_line < control::IRQ_LINES);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut input = input;
+        let mut buf = [0; 256];
+        loop {
+            match input.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if buf[..n].iter().any(|&b| sender.send(b).is_err()) {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    Console {
+        input: receiver,
+        next: None,
+        closed: false,
+        output,
+        irq_enabled: false,
+        irq_line,
+    }
+}
+
+/// Creates a console on the host's stdin and stdout.
+pub fn stdio(irq_line: u8) -> Console {
+    new(Box::new(io::stdin()), Box::new(io::stdout()), irq_line)
+}
+
+/// Creates a console reading from and writing to files; `None` selects
+/// stdin or stdout.
+pub fn files(input: Option<&str>, output: Option<&str>, irq_line: u8) -> io::Result<Console> {
+    let input: Box<dyn Read + Send> = match input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    };
+    let output: Box<dyn Write + Send> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    Ok(new(input, output, irq_line))
+}
+
+impl Console {
+    fn poll(&mut self) {
+        if self.next.is_none() && !self.closed {
+            match self.input.try_recv() {
+                Ok(b) => self.next = Some(b),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => self.closed = true,
+            }
+        }
+    }
+}
+
+impl Device for Console {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.poll();
+        match offset {
+            DATA => self.next.take().unwrap_or(0),
+            _ => self.peek(offset),
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            DATA => {
+                // a console has nowhere to report a failed write
+                let _ = self.output.write_all(&[value]);
+                let _ = self.output.flush();
+            }
+            _ => self.irq_enabled = value & STATUS_RX_IRQ != 0,
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            DATA => self.next.unwrap_or(0),
+            _ => {
+                let mut status = STATUS_TX_READY;
+                if self.next.is_some() {
+                    status |= STATUS_RX_READY;
+                } else if self.closed {
+                    status |= STATUS_RX_CLOSED;
+                }
+                if self.irq_enabled {
+                    status |= STATUS_RX_IRQ;
+                }
+                status
+            }
+        }
+    }
+
+    fn tick(&mut self) -> u8 {
+        self.poll();
+        if self.irq_enabled && (self.next.is_some() || self.closed) {
+            1 << self.irq_line
+        } else {
+            0
+        }
+    }
+}
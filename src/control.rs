@@ -1,9 +1,15 @@
 use crate::alu;
+use crate::config::{self, Config};
 use crate::memory;
-use std::cell::UnsafeCell;
+use crate::memory::{
+    Access, Cause, LoadError, MemFault, MemWrite, WatchHit, WatchKind, Watchpoint,
+};
+use crate::savestate;
+use crate::trace::{Step, Tracer};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::num::Wrapping;
-use std::sync::{Arc, RwLock};
-use std::thread;
 
 // bytecode!
 
@@ -12,9 +18,10 @@ pub const WAIT: u8 = 0b00000000; // pause execution until interrupt
 pub const RESET: u8 = 0b00000001; // reset everything
 
 pub const OVERFLOW: u8 = 0b00000010;
+pub const RETURN_INT: u8 = 0b00000011; // return from interrupt
 
 pub const BRANCH: u8 = 0b00000100; // add top of stack to inst ptr
-pub const BRANCH_S: u8 = 0b00000101; // same but uses sign-magnitude
+pub const BRANCH_S: u8 = 0b00000101; // same but two's complement
 
 pub const ENTER: u8 = 0b00000110; // new local variable pointer
 pub const LEAVE: u8 = 0b00000111; // restore
@@ -48,6 +55,7 @@ pub const LOAD: u8 = 0b00011100;
 pub const SAVE: u8 = 0b00011101;
 
 pub const DUP_B: u8 = 0b00011110; // duplicate byte atop stack
+pub const RETURN: u8 = 0b00011111; // pop return address; was DUP_D
 
 pub const CLEAR_FLAGS: u8 = 0b00100000;
 pub const TEST: u8 = 0b00100001;
@@ -75,10 +83,10 @@ pub const IF_ODD: u8 = 0b00110100;
 pub const IF_EVEN: u8 = 0b00110101;
 pub const IF_OVERFLOW: u8 = 0b00110110;
 pub const IF_NO_OVERFLOW: u8 = 0b00110111;
-pub const IF_GREATER_EQUAL: u8 = 0b00111000;
-pub const IF_LESS_EQUAL: u8 = 0b00111001;
-pub const IF_GREATER: u8 = 0b00111010;
-pub const IF_LESS: u8 = 0b00111011;
+pub const IF_GREATER_EQUAL: u8 = 0b00111000; // signed >=
+pub const IF_LESS_EQUAL: u8 = 0b00111001; // signed <=
+pub const IF_GREATER: u8 = 0b00111010; // signed >
+pub const IF_LESS: u8 = 0b00111011; // signed <
 pub const IF_HIGHER: u8 = 0b00111100; // unsigned >
 pub const IF_LOWER: u8 = 0b00111101; // unsigned <
 pub const IF_CARRY: u8 = 0b00111110;
@@ -86,13 +94,58 @@ pub const IF_NO_CARRY: u8 = 0b00111111;
 
 // increment 2
 pub const IMM_BRANCH: u8 = 0b01000000; // add immediate byte to inst ptr
-pub const IMM_BRANCH_S: u8 = 0b01000001; // same but respects sign
+pub const IMM_BRANCH_S: u8 = 0b01000001; // same but two's complement
 pub const IMM_CONST: u8 = 0b01000010; // push immediate value
+pub const IMPL_DEP_2: u8 = 0b01000011;
+pub const ENABLE_INT: u8 = 0b01000100; // enable interrupt lines in mask
+pub const DISABLE_INT: u8 = 0b01000101; // disable interrupt lines in mask
 pub const LOCAL: u8 = 0b01001000;
 
+// double-width ALU operations, on words; those that do not use their
+// immediate byte take it as 0
+pub const INC_W: u8 = 0b01100000; // add immediate to word
+pub const TEST_W: u8 = 0b01100001;
+pub const ADD_W: u8 = 0b01100010;
+pub const ADD_CARRY_W: u8 = 0b01100011;
+pub const SUBTRACT_W: u8 = 0b01100100;
+pub const SUB_BORROW_W: u8 = 0b01100101;
+pub const DEC_W: u8 = 0b01100110; // subtract immediate from word
+pub const COMPARE_W: u8 = 0b01100111;
+pub const SHIFT_LEFT_W: u8 = 0b01101000; // shift by immediate
+pub const SHIFT_RIGHT_W: u8 = 0b01101001;
+pub const ROTATE_LEFT_W: u8 = 0b01101010;
+pub const ROTATE_RIGHT_W: u8 = 0b01101011;
+pub const NOT_W: u8 = 0b01101100;
+pub const AND_W: u8 = 0b01101101;
+pub const INCLUSIVE_OR_W: u8 = 0b01101110;
+pub const EXCLUSIVE_OR_W: u8 = 0b01101111;
+
+// division, of a byte or word by a byte; no operand
+pub const DIVIDE: u8 = 0b01110000;
+pub const MODULO: u8 = 0b01110001;
+pub const DIVIDE_S: u8 = 0b01110010; // signed
+pub const MODULO_S: u8 = 0b01110011;
+pub const DIVIDE_W: u8 = 0b01110100; // word by byte
+pub const MODULO_W: u8 = 0b01110101;
+pub const DIVIDE_WS: u8 = 0b01110110;
+pub const MODULO_WS: u8 = 0b01110111;
+
+// multiplication; no operand
+pub const MULTIPLY_S: u8 = 0b01111000; // signed
+pub const MULTIPLY_D: u8 = 0b01111001; // push the whole product
+pub const MULTIPLY_DS: u8 = 0b01111010;
+
+// packed BCD arithmetic; no operand
+pub const ADD_DECIMAL: u8 = 0b01111011;
+pub const ADD_CARRY_DECIMAL: u8 = 0b01111100;
+pub const SUB_DECIMAL: u8 = 0b01111101;
+pub const SUB_BORROW_DECIMAL: u8 = 0b01111110;
+
 // increment 3
 pub const GOTO: u8 = 0b10000010; // set instruction pointer
 pub const SET_STACK: u8 = 0b10000011; // set stack pointer
+pub const SET_VECTORS: u8 = 0b10000100; // set interrupt vector table
+pub const SET_LIMIT: u8 = 0b10000101; // set stack limit
 pub const IMM_LOAD: u8 = 0b10001100; // load from immediate address
 pub const IMM_LOAD_OFFSET_B: u8 = 0b10001101; // above plus top byte of stack
 pub const IMPL_DEP_0: u8 = 0b10001111;
@@ -101,6 +154,296 @@ pub const IMM_SAVE: u8 = 0b10010100; // save to immediate address
 pub const IMM_SAVE_OFFSET_B: u8 = 0b10010101; // above plus top byte of stack
 pub const IMPL_DEP_1: u8 = 0b10010111;
 
+// mnemonics; where two names share an opcode the first is preferred
+pub const OPCODES: &[(&str, u8)] = &[
+    ("WAIT", WAIT),
+    ("RESET", RESET),
+    ("OVERFLOW", OVERFLOW),
+    ("RETURN_INT", RETURN_INT),
+    ("BRANCH", BRANCH),
+    ("BRANCH_S", BRANCH_S),
+    ("ENTER", ENTER),
+    ("LEAVE", LEAVE),
+    ("LOAD_0", LOAD_0),
+    ("LOAD_1", LOAD_1),
+    ("LOAD_2", LOAD_2),
+    ("LOAD_3", LOAD_3),
+    ("UNLINK", UNLINK),
+    ("LINK", LINK),
+    ("CALL", CALL),
+    ("GOBACK", GOBACK),
+    ("SAVE_0", SAVE_0),
+    ("SAVE_1", SAVE_1),
+    ("SAVE_2", SAVE_2),
+    ("SAVE_3", SAVE_3),
+    ("LOCAL_0", LOCAL_0),
+    ("LOCAL_1", LOCAL_1),
+    ("LOCAL_2", LOCAL_2),
+    ("LOCAL_3", LOCAL_3),
+    ("CONST_0", CONST_0),
+    ("CONST_1", CONST_1),
+    ("CONST_2", CONST_2),
+    ("CONST_3", CONST_3),
+    ("LOAD", LOAD),
+    ("SAVE", SAVE),
+    ("DUP_B", DUP_B),
+    ("RETURN", RETURN),
+    ("CLEAR_FLAGS", CLEAR_FLAGS),
+    ("TEST", TEST),
+    ("ADD", ADD),
+    ("ADD_CARRY", ADD_CARRY),
+    ("SUBTRACT", SUBTRACT),
+    ("SUB_BORROW", SUB_BORROW),
+    ("MULTIPLY", MULTIPLY),
+    ("COMPARE", COMPARE),
+    ("SHIFT_LEFT", SHIFT_LEFT),
+    ("SHIFT_RIGHT", SHIFT_RIGHT),
+    ("ROTATE_LEFT", ROTATE_LEFT),
+    ("ROTATE_RIGHT", ROTATE_RIGHT),
+    ("NOT", NOT),
+    ("AND", AND),
+    ("INCLUSIVE_OR", INCLUSIVE_OR),
+    ("EXCLUSIVE_OR", EXCLUSIVE_OR),
+    ("IF_EQUAL", IF_EQUAL),
+    ("IF_UNEQUAL", IF_UNEQUAL),
+    ("IF_POSITIVE", IF_POSITIVE),
+    ("IF_NEGATIVE", IF_NEGATIVE),
+    ("IF_ODD", IF_ODD),
+    ("IF_EVEN", IF_EVEN),
+    ("IF_OVERFLOW", IF_OVERFLOW),
+    ("IF_NO_OVERFLOW", IF_NO_OVERFLOW),
+    ("IF_GREATER_EQUAL", IF_GREATER_EQUAL),
+    ("IF_LESS_EQUAL", IF_LESS_EQUAL),
+    ("IF_GREATER", IF_GREATER),
+    ("IF_LESS", IF_LESS),
+    ("IF_HIGHER", IF_HIGHER),
+    ("IF_LOWER", IF_LOWER),
+    ("IF_CARRY", IF_CARRY),
+    ("IF_NO_CARRY", IF_NO_CARRY),
+    ("IMM_BRANCH", IMM_BRANCH),
+    ("IMM_BRANCH_S", IMM_BRANCH_S),
+    ("IMM_CONST", IMM_CONST),
+    ("IMPL_DEP_2", IMPL_DEP_2),
+    ("ENABLE_INT", ENABLE_INT),
+    ("DISABLE_INT", DISABLE_INT),
+    ("LOCAL", LOCAL),
+    ("INC_W", INC_W),
+    ("TEST_W", TEST_W),
+    ("ADD_W", ADD_W),
+    ("ADD_CARRY_W", ADD_CARRY_W),
+    ("SUBTRACT_W", SUBTRACT_W),
+    ("SUB_BORROW_W", SUB_BORROW_W),
+    ("DEC_W", DEC_W),
+    ("COMPARE_W", COMPARE_W),
+    ("SHIFT_LEFT_W", SHIFT_LEFT_W),
+    ("SHIFT_RIGHT_W", SHIFT_RIGHT_W),
+    ("ROTATE_LEFT_W", ROTATE_LEFT_W),
+    ("ROTATE_RIGHT_W", ROTATE_RIGHT_W),
+    ("NOT_W", NOT_W),
+    ("AND_W", AND_W),
+    ("INCLUSIVE_OR_W", INCLUSIVE_OR_W),
+    ("EXCLUSIVE_OR_W", EXCLUSIVE_OR_W),
+    ("DIVIDE", DIVIDE),
+    ("MODULO", MODULO),
+    ("DIVIDE_S", DIVIDE_S),
+    ("MODULO_S", MODULO_S),
+    ("DIVIDE_W", DIVIDE_W),
+    ("MODULO_W", MODULO_W),
+    ("DIVIDE_WS", DIVIDE_WS),
+    ("MODULO_WS", MODULO_WS),
+    ("MULTIPLY_S", MULTIPLY_S),
+    ("MULTIPLY_D", MULTIPLY_D),
+    ("MULTIPLY_DS", MULTIPLY_DS),
+    ("ADD_DECIMAL", ADD_DECIMAL),
+    ("ADD_CARRY_DECIMAL", ADD_CARRY_DECIMAL),
+    ("SUB_DECIMAL", SUB_DECIMAL),
+    ("SUB_BORROW_DECIMAL", SUB_BORROW_DECIMAL),
+    ("GOTO", GOTO),
+    ("SET_STACK", SET_STACK),
+    ("SET_VECTORS", SET_VECTORS),
+    ("SET_LIMIT", SET_LIMIT),
+    ("IMM_LOAD", IMM_LOAD),
+    ("IMM_LOAD_OFFSET_B", IMM_LOAD_OFFSET_B),
+    ("IMM_CONST_D", IMM_CONST_D),
+    ("IMPL_DEP_0", IMPL_DEP_0),
+    ("IMM_SAVE", IMM_SAVE),
+    ("IMM_SAVE_OFFSET_B", IMM_SAVE_OFFSET_B),
+    ("IMPL_DEP_1", IMPL_DEP_1),
+];
+
+/// Whether an instruction's bytes after the opcode are an operand; the
+/// ALU operations that do not use their immediate byte take it as 0 and
+/// have none.
+pub fn takes_operand(opcode: u8) -> bool {
+    match opcode {
+        TEST_W | ADD_W | ADD_CARRY_W | SUBTRACT_W | SUB_BORROW_W | COMPARE_W | NOT_W | AND_W
+        | INCLUSIVE_OR_W | EXCLUSIVE_OR_W => false,
+        DIVIDE..=SUB_BORROW_DECIMAL => false,
+        _ => instruction_length(opcode) > 1,
+    }
+}
+
+/// Looks up the opcode for a mnemonic, ignoring case.
+pub fn opcode(mnemonic: &str) -> Option<u8> {
+    OPCODES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(mnemonic))
+        .map(|&(_, op)| op)
+}
+
+/// Looks up the preferred mnemonic for an opcode.
+pub fn mnemonic(opcode: u8) -> Option<&'static str> {
+    OPCODES
+        .iter()
+        .find(|&&(_, op)| op == opcode)
+        .map(|&(name, _)| name)
+}
+
+// the bytes an instruction takes from the top of the stack, popped or
+// read, and the bytes it leaves in their place
+fn stack_effect(opcode: u8) -> (u16, u16) {
+    match opcode {
+        RETURN_INT => (6, 0),
+        SAVE => (3, 0),
+        LOAD | ADD | ADD_CARRY | SUBTRACT | SUB_BORROW | MULTIPLY | SHIFT_LEFT | SHIFT_RIGHT
+        | ROTATE_LEFT | ROTATE_RIGHT | AND | INCLUSIVE_OR | EXCLUSIVE_OR => (2, 1),
+        LINK | CALL | RETURN | COMPARE | IMM_SAVE_OFFSET_B | TEST_W => (2, 0),
+        ADD_W | ADD_CARRY_W | SUBTRACT_W | SUB_BORROW_W | AND_W | INCLUSIVE_OR_W
+        | EXCLUSIVE_OR_W => (4, 2),
+        COMPARE_W => (4, 0),
+        DIVIDE | MODULO | DIVIDE_S | MODULO_S | MULTIPLY_S => (2, 1),
+        ADD_DECIMAL..=SUB_BORROW_DECIMAL => (2, 1),
+        MULTIPLY_D | MULTIPLY_DS => (2, 2),
+        DIVIDE_W | MODULO_W | DIVIDE_WS | MODULO_WS => (3, 1),
+        INC_W | DEC_W | SHIFT_LEFT_W | SHIFT_RIGHT_W | ROTATE_LEFT_W | ROTATE_RIGHT_W | NOT_W => {
+            (2, 2)
+        }
+        DUP_B => (1, 2),
+        NOT | IMM_LOAD_OFFSET_B => (1, 1),
+        BRANCH | BRANCH_S | SAVE_0 | SAVE_1 | SAVE_2 | SAVE_3 | TEST | IMM_SAVE => (1, 0),
+        UNLINK | ENTER | IMM_CONST_D => (0, 2),
+        OVERFLOW | LOAD_0 | LOAD_1 | LOAD_2 | LOAD_3 | LOCAL_0 | LOCAL_1 | LOCAL_2 | LOCAL_3
+        | CONST_0 | CONST_1 | CONST_2 | CONST_3 | IMM_CONST | LOCAL | IMM_LOAD => (0, 1),
+        _ => (0, 0),
+    }
+}
+
+pub const IRQ_LINES: u8 = 8;
+
+// by default the vector table occupies the top 16 bytes of the address space
+pub const DEFAULT_VECTORS: u16 = 0xFFF0;
+
+/// The length in bytes of an instruction, encoded in the top two bits of
+/// its opcode.
+pub fn instruction_length(opcode: u8) -> u16 {
+    1 + (opcode >> 6) as u16
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    Memory(MemFault),
+    /// A push past the stack limit, with SP before the instruction.
+    StackOverflow(u16),
+    /// A pop below the stack base, with SP before the instruction.
+    StackUnderflow(u16),
+}
+
+impl From<MemFault> for FaultKind {
+    fn from(fault: MemFault) -> FaultKind {
+        FaultKind::Memory(fault)
+    }
+}
+
+/// A fault that stopped the machine, and the address of the instruction
+/// that raised it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub ip: u16,
+    pub kind: FaultKind,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            FaultKind::Memory(fault) => write!(f, "{} (IP {:04X})", fault, self.ip),
+            FaultKind::StackOverflow(sp) => {
+                write!(f, "stack overflow at SP {:04X} (IP {:04X})", sp, self.ip)
+            }
+            FaultKind::StackUnderflow(sp) => {
+                write!(f, "stack underflow at SP {:04X} (IP {:04X})", sp, self.ip)
+            }
+        }
+    }
+}
+
+/// Where CALL saves the return address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallMode {
+    /// In LN, for GOBACK; a subroutine that calls another must save LN
+    /// with UNLINK and restore it with LINK itself.
+    Link,
+    /// Pushed on the stack (low, high) for RETURN, so calls nest.
+    Stack,
+}
+
+/// A watchpoint hit that stopped the machine, and the address of the
+/// instruction that made the access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchStop {
+    pub ip: u16,
+    pub hit: WatchHit,
+}
+
+impl fmt::Display for WatchStop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (IP {:04X})", self.hit, self.ip)
+    }
+}
+
+/// A copy of the programmer-visible registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub instr_ptr: u16,
+    pub stack_ptr: u16,
+    pub link: u16,
+    pub local: u16,
+    pub save: [u8; 4],
+    pub flags: u8,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "IP: {:04X} SP: {:04X}", self.instr_ptr, self.stack_ptr)?;
+        writeln!(f, "LN: {:04X} LO: {:04X}", self.link, self.local)?;
+        writeln!(f, "S0:   {:02X} S1:   {:02X}", self.save[0], self.save[1])?;
+        writeln!(f, "S2:   {:02X} S3:   {:02X}", self.save[2], self.save[3])?;
+        write!(f, "FL: {:06b}", self.flags)
+    }
+}
+
+// Everything one step can change, as it was before the step.
+struct Undo {
+    registers: Registers,
+    alu: [u8; alu::REGISTERS],
+    running: bool,
+    waiting: bool,
+    fault: Option<Fault>,
+    watch_stop: Option<WatchStop>,
+    vector_base: u16,
+    int_mask: u8,
+    int_pending: u8,
+    stack_base: u16,
+    stack_limit: Option<u16>,
+    mar: u16,
+    writes: Vec<MemWrite>,
+}
+
+struct History {
+    steps: VecDeque<Undo>,
+    capacity: usize,
+}
+
+/// The control unit: registers, the ALU and memory.
 pub struct Control {
     instr_ptr: Wrapping<u16>,
     stack_ptr: Wrapping<u16>,
@@ -113,14 +456,50 @@ pub struct Control {
     link: u16,
     local: u16,
     running: bool,
+    waiting: bool,
+    fault: Option<Fault>,
+    watch_stop: Option<WatchStop>,
+    tracer: Option<Box<dyn Tracer>>,
+    history: Option<History>,
+    vector_base: u16,
+    int_mask: u8,
+    int_pending: u8,
+    // pops may not take SP below the base nor pushes take it past the
+    // limit; unchecked without a limit
+    stack_base: u16,
+    stack_limit: Option<u16>,
+    call_mode: CallMode,
 }
 
+/// Creates a halted control unit with all registers cleared and the
+/// default configuration; load an image with `load_image` before starting
+/// it.
 pub fn new() -> Control {
+    from_config(&config::new())
+}
+
+/// Creates a halted control unit with all registers cleared and zeroed
+/// memory laid out as `config` describes. The configured images are not
+/// loaded; see `Config::load_images`.
+pub fn from_config(config: &Config) -> Control {
+    let mut mem = memory::new(config.mem_size);
+    for region in &config.regions {
+        mem.map_region(region.start, region.end, region.kind);
+    }
+    mem.set_rom_writes(config.rom_writes);
+    if let Some(banking) = config.banking {
+        mem.map_banks(banking);
+    }
+    let (stack_base, stack_limit) = match config.stack {
+        Some((base, limit)) => (base, Some(limit)),
+        None => (0, None),
+    };
+
     Control {
         instr_ptr: Wrapping(0),
-        stack_ptr: Wrapping(0),
+        stack_ptr: Wrapping(stack_base),
         alu: alu::new(),
-        mem: memory::new(0),
+        mem,
         save_0: 0,
         save_1: 0,
         save_2: 0,
@@ -128,87 +507,886 @@ pub fn new() -> Control {
         link: 0,
         local: 0,
         running: false,
+        waiting: false,
+        fault: None,
+        watch_stop: None,
+        tracer: None,
+        history: None,
+        vector_base: DEFAULT_VECTORS,
+        int_mask: 0,
+        int_pending: 0,
+        stack_base,
+        stack_limit,
+        call_mode: config.call_mode,
     }
 }
 
 macro_rules! push {
     ($slf:expr, $x:expr) => {
-        ($slf).stack_ptr += Wrapping(1);
-        ($slf).mem.set_addr(($slf).stack_ptr.0);
-        ($slf).mem.write(($x));
+        $slf.stack_ptr += Wrapping(1);
+        $slf.mem.set_addr($slf.stack_ptr.0);
+        $slf.mem.write($x)?;
     };
 }
 
 macro_rules! alu_op {
     ($slf:expr, $x:expr) => {
-        ($slf).mem.set_addr(($slf).stack_ptr.0);
-        ($slf).alu.load_y(($slf).mem.read());
-        ($slf).mem.set_addr((($slf).stack_ptr - Wrapping(1)).0);
-        ($slf).alu.load_x(($slf).mem.read());
-        ($slf).alu.load_op(($x));
-        ($slf).alu.compute();
-        ($slf).stack_ptr -= Wrapping(1);
-        ($slf).mem.set_addr(($slf).stack_ptr.0);
-        ($slf).mem.write(($slf).alu.result());
+        $slf.mem.set_addr($slf.stack_ptr.0);
+        $slf.alu.load_y($slf.mem.read()?);
+        $slf.mem.set_addr(($slf.stack_ptr - Wrapping(1)).0);
+        $slf.alu.load_x($slf.mem.read()?);
+        $slf.alu.load_op($x);
+        $slf.alu.compute();
+        $slf.stack_ptr -= Wrapping(1);
+        $slf.mem.set_addr($slf.stack_ptr.0);
+        $slf.mem.write($slf.alu.result())?;
+    };
+}
+
+// Y is the word on top of the stack and X the one below it
+macro_rules! alu_op_w {
+    ($slf:expr, $x:expr) => {
+        let y = $slf.stack_word(0)?;
+        let x = $slf.stack_word(2)?;
+        $slf.alu.load_x_wide(x);
+        $slf.alu.load_y_wide(y);
+        $slf.alu.load_op(alu::ALU_WIDE + $x);
+        $slf.alu.compute();
+        $slf.stack_ptr -= Wrapping(2);
+        $slf.set_stack_word($slf.alu.result_wide())?;
+    };
+}
+
+// X is the word on top of the stack and Y the immediate byte
+macro_rules! alu_imm_w {
+    ($slf:expr, $x:expr, $y:expr) => {
+        let x = $slf.stack_word(0)?;
+        $slf.alu.load_x_wide(x);
+        $slf.alu.load_y_wide($y as u16);
+        $slf.alu.load_op(alu::ALU_WIDE + $x);
+        $slf.alu.compute();
+        $slf.set_stack_word($slf.alu.result_wide())?;
     };
 }
 
 macro_rules! local {
     ($slf:expr, $x:expr) => {
-        let address = (Wrapping(($slf).local) + Wrapping(($x))).0;
-        ($slf).mem.set_addr(address);
-        let x = ($slf).mem.read();
-        push!(($slf), x);
+        let address = (Wrapping($slf.local) + Wrapping($x)).0;
+        $slf.mem.set_addr(address);
+        let x = $slf.mem.read()?;
+        push!($slf, x);
     };
 }
 
 macro_rules! cond {
     ($slf:expr, $x:expr) => {
-        ($slf).mem.set_addr(($slf).instr_ptr.0);
-        let skip_distance = (($slf).mem.read() >> 6) as u16;
-        if !($x) {
-            ($slf).instr_ptr += Wrapping(skip_distance + 1);
+        $slf.mem.set_addr($slf.instr_ptr.0);
+        let skip_distance = ($slf.mem.read()? >> 6) as u16;
+        if !$x {
+            $slf.instr_ptr += Wrapping(skip_distance + 1);
         }
     };
 }
 
 impl Control {
-    pub fn load_image(&mut self, image: Vec<u8>) {
-        self.mem.load_image(image);
+    /// Copies `image` into memory starting at `base`, discarding any
+    /// history.
+    pub fn load_image(&mut self, base: u16, image: &[u8]) -> Result<(), LoadError> {
+        self.mem.load_image(base, image)?;
+        self.clear_history();
+        Ok(())
+    }
+
+    /// Copies `image` into `bank` as if loaded at `base` while the bank is
+    /// selected, discarding any history.
+    pub fn load_bank(&mut self, bank: u8, base: u16, image: &[u8]) -> Result<(), LoadError> {
+        self.mem.load_bank(bank, base, image)?;
+        self.clear_history();
+        Ok(())
     }
 
+    fn clear_history(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.steps.clear();
+        }
+    }
+
+    /// Starts the machine, clearing any fault or watchpoint stop.
     pub fn start(&mut self) {
+        self.fault = None;
+        self.watch_stop = None;
+        self.waiting = false;
         self.running = true;
     }
 
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
     pub fn is_running(&self) -> bool {
         self.running
     }
 
+    /// Whether the machine is stopped in WAIT; an enabled interrupt
+    /// restarts it.
+    pub fn is_waiting(&self) -> bool {
+        self.waiting
+    }
+
+    /// Raises interrupt request line `line`; it stays pending until the
+    /// machine takes it.
+    pub fn raise_irq(&mut self, line: u8) {
+        assert!(line < IRQ_LINES);
+        self.int_pending |= 1 << line;
+        if self.waiting && self.int_pending & self.int_mask != 0 {
+            self.waiting = false;
+            self.running = true;
+        }
+    }
+
+    /// Ticks the devices on the memory bus and raises the interrupt lines
+    /// they request. Called after every instruction; hosts should also call
+    /// it while the machine waits.
+    pub fn tick(&mut self) {
+        let lines = self.mem.tick();
+        for line in 0..IRQ_LINES {
+            if lines & 1 << line != 0 {
+                self.raise_irq(line);
+            }
+        }
+    }
+
+    pub fn clear_irq(&mut self, line: u8) {
+        assert!(line < IRQ_LINES);
+        self.int_pending &= !(1 << line);
+    }
+
+    /// The pending interrupt request lines, one bit per line.
+    pub fn pending_irqs(&self) -> u8 {
+        self.int_pending
+    }
+
+    /// The enabled interrupt lines, one bit per line.
+    pub fn int_mask(&self) -> u8 {
+        self.int_mask
+    }
+
+    pub fn set_int_mask(&mut self, mask: u8) {
+        self.int_mask = mask;
+    }
+
+    pub fn vector_base(&self) -> u16 {
+        self.vector_base
+    }
+
+    pub fn set_vector_base(&mut self, addr: u16) {
+        self.vector_base = addr;
+    }
+
+    /// The fault that stopped the machine, if any.
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    pub fn clear_fault(&mut self) {
+        self.fault = None;
+    }
+
+    /// Stops the machine after any instruction that makes a `kind` access
+    /// to `start..=end` through the memory address register.
+    pub fn watch(&mut self, start: u16, end: u16, kind: WatchKind) {
+        self.mem.watch(start, end, kind);
+    }
+
+    pub fn unwatch(&mut self, start: u16, end: u16, kind: WatchKind) -> bool {
+        self.mem.unwatch(start, end, kind)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        self.mem.watchpoints()
+    }
+
+    /// Hands every step executed from now on to `tracer`, replacing any
+    /// previous tracer.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+        self.update_journal();
+    }
+
+    /// Removes the tracer, if any, so that it can be finished.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        let tracer = self.tracer.take();
+        self.update_journal();
+        tracer
+    }
+
+    // the journal is only kept while something consumes it
+    fn update_journal(&mut self) {
+        self.mem
+            .set_journal(self.tracer.is_some() || self.history.is_some());
+    }
+
+    /// Keeps enough history to undo the last `capacity` steps, discarding
+    /// any history kept so far; 0 turns history off.
+    ///
+    /// Changes made from outside, through setters or `memory_mut`, are not
+    /// recorded, and neither are the side effects of device accesses.
+    pub fn set_history(&mut self, capacity: usize) {
+        self.history = match capacity {
+            0 => None,
+            _ => Some(History {
+                steps: VecDeque::with_capacity(capacity.min(1 << 16)),
+                capacity,
+            }),
+        };
+        self.update_journal();
+    }
+
+    /// How many steps can currently be undone.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |h| h.steps.len())
+    }
+
+    /// Undoes the most recent step, returning the memory writes it undid,
+    /// or None if there is no history left.
+    pub fn step_back(&mut self) -> Option<Vec<MemWrite>> {
+        let undo = self.history.as_mut()?.steps.pop_back()?;
+
+        for write in undo.writes.iter().rev() {
+            self.mem.undo(write);
+        }
+        self.set_registers(&undo.registers);
+        self.alu.set_registers(undo.alu);
+        self.running = undo.running;
+        self.waiting = undo.waiting;
+        self.fault = undo.fault;
+        self.watch_stop = undo.watch_stop;
+        self.vector_base = undo.vector_base;
+        self.int_mask = undo.int_mask;
+        self.int_pending = undo.int_pending;
+        self.stack_base = undo.stack_base;
+        self.stack_limit = undo.stack_limit;
+        self.mem.set_addr(undo.mar);
+        Some(undo.writes)
+    }
+
+    fn undo_record(&self) -> Undo {
+        Undo {
+            registers: self.registers(),
+            alu: self.alu.registers(),
+            running: self.running,
+            waiting: self.waiting,
+            fault: self.fault,
+            watch_stop: self.watch_stop,
+            vector_base: self.vector_base,
+            int_mask: self.int_mask,
+            int_pending: self.int_pending,
+            stack_base: self.stack_base,
+            stack_limit: self.stack_limit,
+            mar: self.mem.addr(),
+            writes: Vec::new(),
+        }
+    }
+
+    /// The watchpoint hit that stopped the machine, until it is started
+    /// again.
+    pub fn watch_stop(&self) -> Option<WatchStop> {
+        self.watch_stop
+    }
+
+    /// Executes a single instruction if the machine is running; returns
+    /// whether it is still running afterwards.
+    pub fn step(&mut self) -> bool {
+        if self.running {
+            self.execute_instruction();
+        }
+        self.running
+    }
+
+    /// Executes instructions until the machine stops.
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    pub fn instr_ptr(&self) -> u16 {
+        self.instr_ptr.0
+    }
+
+    pub fn set_instr_ptr(&mut self, value: u16) {
+        self.instr_ptr = Wrapping(value);
+    }
+
+    pub fn stack_ptr(&self) -> u16 {
+        self.stack_ptr.0
+    }
+
+    pub fn set_stack_ptr(&mut self, value: u16) {
+        self.stack_ptr = Wrapping(value);
+    }
+
+    /// The lowest SP a pop may leave, set along with SP by SET_STACK.
+    pub fn stack_base(&self) -> u16 {
+        self.stack_base
+    }
+
+    pub fn set_stack_base(&mut self, value: u16) {
+        self.stack_base = value;
+    }
+
+    /// The highest SP a push may leave, set by SET_LIMIT; None if the stack
+    /// is unchecked.
+    pub fn stack_limit(&self) -> Option<u16> {
+        self.stack_limit
+    }
+
+    pub fn set_stack_limit(&mut self, value: Option<u16>) {
+        self.stack_limit = value;
+    }
+
+    pub fn call_mode(&self) -> CallMode {
+        self.call_mode
+    }
+
+    pub fn set_call_mode(&mut self, mode: CallMode) {
+        self.call_mode = mode;
+    }
+
+    pub fn link(&self) -> u16 {
+        self.link
+    }
+
+    pub fn set_link(&mut self, value: u16) {
+        self.link = value;
+    }
+
+    pub fn local(&self) -> u16 {
+        self.local
+    }
+
+    pub fn set_local(&mut self, value: u16) {
+        self.local = value;
+    }
+
+    pub fn save_0(&self) -> u8 {
+        self.save_0
+    }
+
+    pub fn set_save_0(&mut self, value: u8) {
+        self.save_0 = value;
+    }
+
+    pub fn save_1(&self) -> u8 {
+        self.save_1
+    }
+
+    pub fn set_save_1(&mut self, value: u8) {
+        self.save_1 = value;
+    }
+
+    pub fn save_2(&self) -> u8 {
+        self.save_2
+    }
+
+    pub fn set_save_2(&mut self, value: u8) {
+        self.save_2 = value;
+    }
+
+    pub fn save_3(&self) -> u8 {
+        self.save_3
+    }
+
+    pub fn set_save_3(&mut self, value: u8) {
+        self.save_3 = value;
+    }
+
+    /// The ALU flags, HONZVC from bit 5 down to bit 0.
+    pub fn flags(&self) -> u8 {
+        self.alu.flags()
+    }
+
+    pub fn set_flags(&mut self, value: u8) {
+        self.alu.set_flags(value);
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            instr_ptr: self.instr_ptr.0,
+            stack_ptr: self.stack_ptr.0,
+            link: self.link,
+            local: self.local,
+            save: [self.save_0, self.save_1, self.save_2, self.save_3],
+            flags: self.alu.flags(),
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.instr_ptr = Wrapping(registers.instr_ptr);
+        self.stack_ptr = Wrapping(registers.stack_ptr);
+        self.link = registers.link;
+        self.local = registers.local;
+        self.save_0 = registers.save[0];
+        self.save_1 = registers.save[1];
+        self.save_2 = registers.save[2];
+        self.save_3 = registers.save[3];
+        self.alu.set_flags(registers.flags);
+    }
+
+    pub fn alu(&self) -> &alu::ALU {
+        &self.alu
+    }
+
+    pub fn memory(&self) -> &memory::Memory {
+        &self.mem
+    }
+
+    pub fn memory_mut(&mut self) -> &mut memory::Memory {
+        &mut self.mem
+    }
+
+    /// Writes the full machine state in the save state format; see
+    /// `savestate`.
+    pub fn save_state(&self, output: &mut dyn Write) -> io::Result<()> {
+        let mut out = savestate::encoder(output)?;
+        out.word(self.instr_ptr.0)?;
+        out.word(self.stack_ptr.0)?;
+        out.word(self.link)?;
+        out.word(self.local)?;
+        for save in [self.save_0, self.save_1, self.save_2, self.save_3] {
+            out.byte(save)?;
+        }
+
+        out.bool(self.running)?;
+        out.bool(self.waiting)?;
+        out.word(self.vector_base)?;
+        out.byte(self.int_mask)?;
+        out.byte(self.int_pending)?;
+        out.word(self.stack_base)?;
+        out.bool(self.stack_limit.is_some())?;
+        out.word(self.stack_limit.unwrap_or(0))?;
+        out.byte(match self.call_mode {
+            CallMode::Link => 0,
+            CallMode::Stack => 1,
+        })?;
+
+        match self.fault {
+            None => out.byte(0)?,
+            Some(Fault {
+                ip,
+                kind: FaultKind::Memory(fault),
+            }) => {
+                out.byte(1)?;
+                out.word(ip)?;
+                out.word(fault.addr)?;
+                out.byte(match fault.access {
+                    Access::Fetch => 0,
+                    Access::Read => 1,
+                    Access::Write => 2,
+                })?;
+                out.byte(match fault.cause {
+                    Cause::Unmapped => 0,
+                    Cause::ReadOnly => 1,
+                    Cause::NoBank => 2,
+                })?;
+            }
+            Some(Fault {
+                ip,
+                kind: FaultKind::StackOverflow(sp),
+            }) => {
+                out.byte(2)?;
+                out.word(ip)?;
+                out.word(sp)?;
+            }
+            Some(Fault {
+                ip,
+                kind: FaultKind::StackUnderflow(sp),
+            }) => {
+                out.byte(3)?;
+                out.word(ip)?;
+                out.word(sp)?;
+            }
+        }
+
+        for register in self.alu.registers() {
+            out.byte(register)?;
+        }
+        out.word(self.mem.addr())?;
+        out.bytes(self.mem.ram())?;
+
+        let count = self.mem.banking().map_or(0, |b| b.count);
+        out.byte(self.mem.bank())?;
+        out.word(count as u16)?;
+        for bank in 0..count {
+            out.bytes(self.mem.bank_contents(bank as u8).expect("bank exists"))?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the machine state with one written by `save_state`. Mapped
+    /// devices, watchpoints and the tracer are kept and history is
+    /// discarded; on error the state is left unchanged.
+    pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        let mut state = savestate::decoder(input)?;
+        let instr_ptr = state.word()?;
+        let stack_ptr = state.word()?;
+        let link = state.word()?;
+        let local = state.word()?;
+        let mut save = [0; 4];
+        for b in save.iter_mut() {
+            *b = state.byte()?;
+        }
+
+        let running = state.bool()?;
+        let waiting = state.bool()?;
+        let vector_base = state.word()?;
+        let int_mask = state.byte()?;
+        let int_pending = state.byte()?;
+        let stack_base = state.word()?;
+        let stack_limit = match (state.bool()?, state.word()?) {
+            (true, limit) => Some(limit),
+            (false, _) => None,
+        };
+        let call_mode = match state.byte()? {
+            0 => CallMode::Link,
+            1 => CallMode::Stack,
+            _ => return Err(savestate::invalid("bad call mode in save state")),
+        };
+
+        let fault = match state.byte()? {
+            0 => None,
+            1 => {
+                let ip = state.word()?;
+                let addr = state.word()?;
+                let access = match state.byte()? {
+                    0 => Access::Fetch,
+                    1 => Access::Read,
+                    2 => Access::Write,
+                    _ => return Err(savestate::invalid("bad memory access in save state")),
+                };
+                let cause = match state.byte()? {
+                    0 => Cause::Unmapped,
+                    1 => Cause::ReadOnly,
+                    2 => Cause::NoBank,
+                    _ => return Err(savestate::invalid("bad fault cause in save state")),
+                };
+                Some(Fault {
+                    ip,
+                    kind: FaultKind::Memory(MemFault {
+                        addr,
+                        access,
+                        cause,
+                    }),
+                })
+            }
+            2 => Some(Fault {
+                ip: state.word()?,
+                kind: FaultKind::StackOverflow(state.word()?),
+            }),
+            3 => Some(Fault {
+                ip: state.word()?,
+                kind: FaultKind::StackUnderflow(state.word()?),
+            }),
+            _ => return Err(savestate::invalid("bad fault in save state")),
+        };
+
+        let mut alu = [0; alu::REGISTERS];
+        for b in alu.iter_mut() {
+            *b = state.byte()?;
+        }
+        let mar = state.word()?;
+        let ram = state.bytes()?;
+        if ram.len() != self.mem.size() {
+            return Err(savestate::invalid(&format!(
+                "save state has {} bytes of memory, not {}",
+                ram.len(),
+                self.mem.size()
+            )));
+        }
+
+        let bank = state.byte()?;
+        let banking = self.mem.banking();
+        let count = state.word()? as usize;
+        if count != banking.map_or(0, |b| b.count) {
+            return Err(savestate::invalid(&format!(
+                "save state has {} banks, not {}",
+                count,
+                banking.map_or(0, |b| b.count)
+            )));
+        }
+        if bank as usize >= count.max(1) {
+            return Err(savestate::invalid("bad bank in save state"));
+        }
+        let mut banks = Vec::with_capacity(count);
+        for _ in 0..count {
+            let contents = state.bytes()?;
+            let banking = banking.expect("count checked above");
+            if contents.len() != (banking.end - banking.start) as usize + 1 {
+                return Err(savestate::invalid("bank of the wrong size in save state"));
+            }
+            banks.push(contents);
+        }
+
+        self.instr_ptr = Wrapping(instr_ptr);
+        self.stack_ptr = Wrapping(stack_ptr);
+        self.link = link;
+        self.local = local;
+        let [save_0, save_1, save_2, save_3] = save;
+        self.save_0 = save_0;
+        self.save_1 = save_1;
+        self.save_2 = save_2;
+        self.save_3 = save_3;
+        self.running = running;
+        self.waiting = waiting;
+        self.vector_base = vector_base;
+        self.int_mask = int_mask;
+        self.int_pending = int_pending;
+        self.stack_base = stack_base;
+        self.stack_limit = stack_limit;
+        self.call_mode = call_mode;
+        self.fault = fault;
+        self.watch_stop = None;
+        self.alu.set_registers(alu);
+        self.mem
+            .load_image(0, &ram)
+            .expect("memory size checked above");
+        if let Some(banking) = banking {
+            self.mem.select_bank(bank);
+            for (i, contents) in banks.iter().enumerate() {
+                self.mem
+                    .load_bank(i as u8, banking.start, contents)
+                    .expect("bank size checked above");
+            }
+        }
+        self.mem.set_addr(mar);
+        self.clear_history();
+        Ok(())
+    }
+
     pub fn view(&self) {
         println!("IP: {:04X} SP: {:04X}", self.instr_ptr, self.stack_ptr);
         println!("LN: {:04X} LO: {:04X}", self.link, self.local);
         println!("S0:   {:02X} S1:   {:02X}", self.save_0, self.save_1);
-        println!("S2:   {:02X} S3:   {:02X}\n", self.save_2, self.save_3);
+        println!("S2:   {:02X} S3:   {:02X}", self.save_2, self.save_3);
+        if self.mem.banking().is_some() {
+            println!("BK:   {:02X}", self.mem.bank());
+        }
+        if let Some(limit) = self.stack_limit {
+            println!("SB: {:04X} SL: {:04X}", self.stack_base, limit);
+        }
+        println!(
+            "IM:   {:02X} IR:   {:02X}\n",
+            self.int_mask, self.int_pending
+        );
+        if let Some(fault) = self.fault {
+            println!("Fault: {}\n", fault);
+        }
+        if let Some(stop) = self.watch_stop {
+            println!("Watchpoint: {}\n", stop);
+        }
     }
 
+    /// Fetches, decodes and executes the instruction at the instruction
+    /// pointer, regardless of whether the machine is running. If an enabled
+    /// interrupt is pending it is taken instead.
+    ///
+    /// A memory fault stops the machine and leaves the instruction pointer at
+    /// the faulting instruction; other registers may already be updated. A
+    /// watchpoint hit lets the instruction finish, then stops the machine.
     pub fn execute_instruction(&mut self) {
+        let ip = self.instr_ptr;
+        let sp = self.stack_ptr;
+        let undo = self.history.as_ref().map(|_| self.undo_record());
+        // only count accesses made by this instruction
+        self.mem.take_watch_hit();
+        self.mem.take_journal();
+
+        let interrupt = self.int_pending & self.int_mask != 0;
+        let bytes = match &self.tracer {
+            Some(_) if !interrupt => self.instruction_bytes(),
+            _ => Vec::new(),
+        };
+
+        let result = if interrupt {
+            self.interrupt()
+        } else {
+            self.execute()
+        };
+
+        if let Err(fault) = result {
+            self.instr_ptr = ip;
+            self.running = false;
+            self.fault = Some(Fault {
+                ip: ip.0,
+                kind: fault,
+            });
+        }
+
+        if let Some(hit) = self.mem.take_watch_hit() {
+            self.running = false;
+            self.watch_stop = Some(WatchStop { ip: ip.0, hit });
+        }
+
+        let writes = self.mem.take_journal();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.step(&Step {
+                ip: ip.0,
+                interrupt,
+                bytes,
+                sp_before: sp.0,
+                sp_after: self.stack_ptr.0,
+                flags: self.alu.flags(),
+                writes: writes.iter().map(|w| (w.addr, w.new)).collect(),
+            });
+        }
+
+        if let (Some(history), Some(mut undo)) = (&mut self.history, undo) {
+            undo.writes = writes;
+            if history.steps.len() == history.capacity {
+                history.steps.pop_front();
+            }
+            history.steps.push_back(undo);
+        }
+
+        self.tick();
+    }
+
+    // the bytes of the instruction at IP, read without side effects
+    fn instruction_bytes(&self) -> Vec<u8> {
+        let ip = self.instr_ptr.0;
+        let opcode = self.mem.public_read(ip).unwrap_or(0);
+        (0..instruction_length(opcode))
+            .map_while(|i| self.mem.public_read(ip.wrapping_add(i)).ok())
+            .collect()
+    }
+
+    fn fetch(&mut self, offset: u16) -> Result<u8, MemFault> {
+        self.mem.set_addr((self.instr_ptr + Wrapping(offset)).0);
+        self.mem.read().map_err(|fault| MemFault {
+            access: Access::Fetch,
+            ..fault
+        })
+    }
+
+    // push IP, link, flags and the interrupt mask, mask all lines and jump
+    // through the vector of the lowest pending line
+    fn interrupt(&mut self) -> Result<(), FaultKind> {
+        self.check_stack(0, 6)?;
+        let line = (self.int_pending & self.int_mask).trailing_zeros() as u16;
+        self.int_pending &= !(1 << line);
+
+        push!(self, (self.instr_ptr.0 & 0xFF) as u8);
+        push!(self, (self.instr_ptr.0 >> 8 & 0xFF) as u8);
+        push!(self, (self.link & 0xFF) as u8);
+        push!(self, (self.link >> 8 & 0xFF) as u8);
+        push!(self, self.alu.flags());
+        push!(self, self.int_mask);
+        self.int_mask = 0;
+
+        let vector = Wrapping(self.vector_base) + Wrapping(2 * line);
+        self.mem.set_addr(vector.0);
+        let tgt_low = self.mem.read()?;
+        self.mem.set_addr((vector + Wrapping(1)).0);
+        let tgt_high = self.mem.read()?;
+
+        self.instr_ptr = Wrapping((tgt_high as u16) << 8 | (tgt_low as u16));
+        Ok(())
+    }
+
+    // faults if taking `inputs` bytes off the stack would cross the stack
+    // base, or leaving `outputs` in their place would cross the limit; an
+    // SP already outside the bounds crosses neither
+    fn check_stack(&self, inputs: u16, outputs: u16) -> Result<(), FaultKind> {
+        let limit = match self.stack_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let sp = self.stack_ptr.0;
+        if sp.wrapping_sub(self.stack_base) < inputs {
+            return Err(FaultKind::StackUnderflow(sp));
+        }
+        if outputs > inputs && limit.wrapping_sub(sp) < outputs - inputs {
+            return Err(FaultKind::StackOverflow(sp));
+        }
+        Ok(())
+    }
+
+    // the word whose high byte is `depth` bytes below the top of the stack
+    fn stack_word(&mut self, depth: u16) -> Result<u16, MemFault> {
+        let high = self.stack_ptr - Wrapping(depth);
+        self.mem.set_addr(high.0);
+        let value_high = self.mem.read()?;
+        self.mem.set_addr((high - Wrapping(1)).0);
+        let value_low = self.mem.read()?;
+        Ok((value_high as u16) << 8 | value_low as u16)
+    }
+
+    // replaces the word on top of the stack
+    fn set_stack_word(&mut self, value: u16) -> Result<(), MemFault> {
+        self.mem.set_addr((self.stack_ptr - Wrapping(1)).0);
+        self.mem.write((value & 0xFF) as u8)?;
+        self.mem.set_addr(self.stack_ptr.0);
+        self.mem.write((value >> 8) as u8)
+    }
+
+    // divides X, the byte or (for ALU_WIDE) word below the byte Y on top of
+    // the stack, by Y, replacing both with the quotient or the remainder
+    fn divide(&mut self, op: u8, remainder: bool) -> Result<(), MemFault> {
+        self.mem.set_addr(self.stack_ptr.0);
+        self.alu.load_y(self.mem.read()?);
+        let x = if op & alu::ALU_WIDE != 0 {
+            self.stack_word(1)?
+        } else {
+            self.mem.set_addr((self.stack_ptr - Wrapping(1)).0);
+            self.mem.read()? as u16
+        };
+        self.alu.load_x_wide(x);
+        self.alu.load_op(op);
+        self.alu.compute();
+
+        let popped = if op & alu::ALU_WIDE != 0 { 2 } else { 1 };
+        self.stack_ptr -= Wrapping(popped);
+        self.mem.set_addr(self.stack_ptr.0);
+        if remainder {
+            self.mem.write(self.alu.res_hi())
+        } else {
+            self.mem.write(self.alu.result())
+        }
+    }
+
+    // adjusts the byte on top of the stack after a binary add or subtract
+    fn decimal_adjust(&mut self, op: u8) -> Result<(), MemFault> {
+        self.alu.load_x(self.alu.result());
+        self.alu.load_op(op);
+        self.alu.compute();
+        self.mem.set_addr(self.stack_ptr.0);
+        self.mem.write(self.alu.result())
+    }
+
+    fn execute(&mut self) -> Result<(), FaultKind> {
         // fetch instruction
-        // println!("{:04X}", self.instr_ptr.0);
-        self.mem.set_addr(self.instr_ptr.0);
-        let instruction = self.mem.read();
-        self.mem.set_addr(self.instr_ptr.0 + 1);
-        let param_low = self.mem.read();
-        self.mem.set_addr(self.instr_ptr.0 + 2);
-        let param_high = self.mem.read();
+        let instruction = self.fetch(0)?;
+        let length = instruction_length(instruction);
+        let param_low = if length > 1 { self.fetch(1)? } else { 0 };
+        let param_high = if length > 2 { self.fetch(2)? } else { 0 };
         let param_16 = (param_high as u16) << 8 | (param_low as u16);
 
+        // check the stack before anything changes
+        let (inputs, outputs) = match instruction {
+            // LEAVE drops everything above the frame
+            LEAVE => (self.stack_ptr.0.wrapping_sub(self.local.wrapping_sub(3)), 0),
+            _ => stack_effect(instruction),
+        };
+        self.check_stack(inputs, outputs)?;
+
         // decode: calculate increment
-        self.instr_ptr += Wrapping(1 + (instruction >> 6) as u16);
+        self.instr_ptr += Wrapping(length);
 
         // execute
         match instruction {
-            WAIT => self.running = false,
+            WAIT => {
+                self.running = false;
+                self.waiting = true;
+            }
             RESET => {
                 self.instr_ptr = Wrapping(0);
                 self.stack_ptr = Wrapping(0);
@@ -219,9 +1397,61 @@ impl Control {
                 self.save_3 = 0;
                 self.link = 0;
                 self.local = 0;
+                self.vector_base = DEFAULT_VECTORS;
+                self.int_mask = 0;
+                self.int_pending = 0;
+                self.stack_base = 0;
                 self.running = true;
             }
 
+            ENABLE_INT => {
+                self.int_mask |= param_low;
+            }
+            DISABLE_INT => {
+                self.int_mask &= !param_low;
+            }
+            RETURN_INT => {
+                self.mem.set_addr(self.stack_ptr.0);
+                let mask = self.mem.read()?;
+                self.stack_ptr -= Wrapping(1);
+                self.mem.set_addr(self.stack_ptr.0);
+                let flags = self.mem.read()?;
+                self.stack_ptr -= Wrapping(1);
+                self.mem.set_addr(self.stack_ptr.0);
+                let link_high = self.mem.read()?;
+                self.stack_ptr -= Wrapping(1);
+                self.mem.set_addr(self.stack_ptr.0);
+                let link_low = self.mem.read()?;
+                self.stack_ptr -= Wrapping(1);
+                self.mem.set_addr(self.stack_ptr.0);
+                let ret_high = self.mem.read()?;
+                self.stack_ptr -= Wrapping(1);
+                self.mem.set_addr(self.stack_ptr.0);
+                let ret_low = self.mem.read()?;
+                self.stack_ptr -= Wrapping(1);
+
+                self.int_mask = mask;
+                self.alu.set_flags(flags);
+                self.link = (link_high as u16) << 8 | (link_low as u16);
+                self.instr_ptr = Wrapping((ret_high as u16) << 8 | (ret_low as u16));
+            }
+            SET_VECTORS => {
+                self.vector_base = param_16;
+            }
+
+            BRANCH => {
+                self.mem.set_addr(self.stack_ptr.0);
+                let offset = self.mem.read()?;
+                self.stack_ptr -= Wrapping(1);
+                self.instr_ptr += Wrapping(offset as u16);
+            }
+            BRANCH_S => {
+                self.mem.set_addr(self.stack_ptr.0);
+                let offset = self.mem.read()?;
+                self.stack_ptr -= Wrapping(1);
+                self.instr_ptr += Wrapping(((offset as i8) as i16) as u16);
+            }
+
             IMM_BRANCH => {
                 self.instr_ptr += Wrapping(param_low as u16);
             }
@@ -232,7 +1462,7 @@ impl Control {
             OVERFLOW => {
                 self.stack_ptr += Wrapping(1);
                 self.mem.set_addr(self.stack_ptr.0);
-                self.mem.write(self.alu.res_hi());
+                self.mem.write(self.alu.res_hi())?;
             }
 
             LOAD_0 => {
@@ -263,7 +1493,7 @@ impl Control {
 
             DUP_B => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let x = self.mem.read();
+                let x = self.mem.read()?;
                 push!(self, x);
             }
 
@@ -273,25 +1503,25 @@ impl Control {
 
             SAVE_0 => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let data = self.mem.read();
+                let data = self.mem.read()?;
                 self.save_0 = data;
                 self.stack_ptr -= Wrapping(1);
             }
             SAVE_1 => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let data = self.mem.read();
+                let data = self.mem.read()?;
                 self.save_1 = data;
                 self.stack_ptr -= Wrapping(1);
             }
             SAVE_2 => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let data = self.mem.read();
+                let data = self.mem.read()?;
                 self.save_2 = data;
                 self.stack_ptr -= Wrapping(1);
             }
             SAVE_3 => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let data = self.mem.read();
+                let data = self.mem.read()?;
                 self.save_3 = data;
                 self.stack_ptr -= Wrapping(1);
             }
@@ -304,10 +1534,10 @@ impl Control {
             }
             LINK => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let ret_high = self.mem.read();
+                let ret_high = self.mem.read()?;
                 self.stack_ptr -= Wrapping(1);
                 self.mem.set_addr(self.stack_ptr.0);
-                let ret_low = self.mem.read();
+                let ret_low = self.mem.read()?;
                 self.stack_ptr -= Wrapping(1);
 
                 self.link = (ret_high as u16) << 8 | (ret_low as u16);
@@ -324,9 +1554,9 @@ impl Control {
             }
             LEAVE => {
                 self.mem.set_addr((Wrapping(self.local) - Wrapping(1)).0);
-                let ret_high = self.mem.read();
+                let ret_high = self.mem.read()?;
                 self.mem.set_addr((Wrapping(self.local) - Wrapping(2)).0);
-                let ret_low = self.mem.read();
+                let ret_low = self.mem.read()?;
                 self.stack_ptr = Wrapping(self.local) - Wrapping(3);
 
                 self.local = (ret_high as u16) << 8 | (ret_low as u16);
@@ -334,17 +1564,33 @@ impl Control {
 
             CALL => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let tgt_high = self.mem.read();
+                let tgt_high = self.mem.read()?;
                 self.stack_ptr -= Wrapping(1);
                 self.mem.set_addr(self.stack_ptr.0);
-                let tgt_low = self.mem.read();
+                let tgt_low = self.mem.read()?;
                 self.stack_ptr -= Wrapping(1);
 
                 let target = (tgt_high as u16) << 8 | (tgt_low as u16);
 
-                self.link = self.instr_ptr.0;
+                match self.call_mode {
+                    CallMode::Link => self.link = self.instr_ptr.0,
+                    CallMode::Stack => {
+                        push!(self, (self.instr_ptr.0 & 0xFF) as u8);
+                        push!(self, (self.instr_ptr.0 >> 8 & 0xFF) as u8);
+                    }
+                }
                 self.instr_ptr = Wrapping(target);
             }
+            RETURN => {
+                self.mem.set_addr(self.stack_ptr.0);
+                let ret_high = self.mem.read()?;
+                self.stack_ptr -= Wrapping(1);
+                self.mem.set_addr(self.stack_ptr.0);
+                let ret_low = self.mem.read()?;
+                self.stack_ptr -= Wrapping(1);
+
+                self.instr_ptr = Wrapping((ret_high as u16) << 8 | (ret_low as u16));
+            }
             GOBACK => {
                 self.instr_ptr = Wrapping(self.link);
             }
@@ -360,24 +1606,24 @@ impl Control {
             }
             TEST => {
                 self.mem.set_addr(self.stack_ptr.0);
-                self.alu.load_x(self.mem.read());
+                self.alu.load_x(self.mem.read()?);
                 self.alu.load_op(alu::ALU_NOP);
                 self.alu.compute();
                 self.stack_ptr -= Wrapping(1);
             }
             NOT => {
                 self.mem.set_addr(self.stack_ptr.0);
-                self.alu.load_x(self.mem.read());
+                self.alu.load_x(self.mem.read()?);
                 self.alu.load_op(alu::ALU_NOT);
                 self.alu.compute();
                 self.mem.set_addr(self.stack_ptr.0);
-                self.mem.write(self.alu.result());
+                self.mem.write(self.alu.result())?;
             }
             COMPARE => {
                 self.mem.set_addr(self.stack_ptr.0);
-                self.alu.load_y(self.mem.read());
+                self.alu.load_y(self.mem.read()?);
                 self.mem.set_addr((self.stack_ptr - Wrapping(1)).0);
-                self.alu.load_x(self.mem.read());
+                self.alu.load_x(self.mem.read()?);
                 self.alu.load_op(alu::ALU_SUB);
                 self.alu.compute();
                 self.stack_ptr -= Wrapping(2);
@@ -419,6 +1665,107 @@ impl Control {
                 alu_op!(self, alu::ALU_XOR);
             }
 
+            INC_W => {
+                alu_imm_w!(self, alu::ALU_ADD, param_low);
+            }
+            TEST_W => {
+                let x = self.stack_word(0)?;
+                self.alu.load_x_wide(x);
+                self.alu.load_op(alu::ALU_WIDE + alu::ALU_NOP);
+                self.alu.compute();
+                self.stack_ptr -= Wrapping(2);
+            }
+            ADD_W => {
+                alu_op_w!(self, alu::ALU_ADD);
+            }
+            ADD_CARRY_W => {
+                alu_op_w!(self, alu::ALU_ADC);
+            }
+            SUBTRACT_W => {
+                alu_op_w!(self, alu::ALU_SUB);
+            }
+            SUB_BORROW_W => {
+                alu_op_w!(self, alu::ALU_SBB);
+            }
+            DEC_W => {
+                alu_imm_w!(self, alu::ALU_SUB, param_low);
+            }
+            COMPARE_W => {
+                let y = self.stack_word(0)?;
+                let x = self.stack_word(2)?;
+                self.alu.load_x_wide(x);
+                self.alu.load_y_wide(y);
+                self.alu.load_op(alu::ALU_WIDE + alu::ALU_SUB);
+                self.alu.compute();
+                self.stack_ptr -= Wrapping(4);
+            }
+            SHIFT_LEFT_W => {
+                alu_imm_w!(self, alu::ALU_SHL, param_low);
+            }
+            SHIFT_RIGHT_W => {
+                alu_imm_w!(self, alu::ALU_SHR, param_low);
+            }
+            ROTATE_LEFT_W => {
+                alu_imm_w!(self, alu::ALU_ROL, param_low);
+            }
+            ROTATE_RIGHT_W => {
+                alu_imm_w!(self, alu::ALU_ROR, param_low);
+            }
+            NOT_W => {
+                alu_imm_w!(self, alu::ALU_NOT, 0);
+            }
+            AND_W => {
+                alu_op_w!(self, alu::ALU_AND);
+            }
+            INCLUSIVE_OR_W => {
+                alu_op_w!(self, alu::ALU_IOR);
+            }
+            EXCLUSIVE_OR_W => {
+                alu_op_w!(self, alu::ALU_XOR);
+            }
+
+            MULTIPLY_S => {
+                alu_op!(self, alu::ALU_MUL + alu::ALU_SIGNED);
+            }
+            MULTIPLY_D => {
+                alu_op!(self, alu::ALU_MUL);
+                push!(self, self.alu.res_hi());
+            }
+            MULTIPLY_DS => {
+                alu_op!(self, alu::ALU_MUL + alu::ALU_SIGNED);
+                push!(self, self.alu.res_hi());
+            }
+
+            ADD_DECIMAL => {
+                alu_op!(self, alu::ALU_ADD);
+                self.decimal_adjust(alu::ALU_DAA)?;
+            }
+            ADD_CARRY_DECIMAL => {
+                alu_op!(self, alu::ALU_ADC);
+                self.decimal_adjust(alu::ALU_DAA)?;
+            }
+            SUB_DECIMAL => {
+                alu_op!(self, alu::ALU_SUB);
+                self.decimal_adjust(alu::ALU_DAS)?;
+            }
+            SUB_BORROW_DECIMAL => {
+                alu_op!(self, alu::ALU_SBB);
+                self.decimal_adjust(alu::ALU_DAS)?;
+            }
+
+            DIVIDE => self.divide(alu::ALU_DIV, false)?,
+            MODULO => self.divide(alu::ALU_DIV, true)?,
+            DIVIDE_S => self.divide(alu::ALU_DIV + alu::ALU_SIGNED, false)?,
+            MODULO_S => self.divide(alu::ALU_DIV + alu::ALU_SIGNED, true)?,
+            DIVIDE_W => self.divide(alu::ALU_DIV + alu::ALU_WIDE, false)?,
+            MODULO_W => self.divide(alu::ALU_DIV + alu::ALU_WIDE, true)?,
+            DIVIDE_WS => {
+                self.divide(alu::ALU_DIV + alu::ALU_WIDE + alu::ALU_SIGNED, false)?;
+            }
+            MODULO_WS => {
+                self.divide(alu::ALU_DIV + alu::ALU_WIDE + alu::ALU_SIGNED, true)?;
+            }
+
             IF_EQUAL => {
                 cond!(self, self.alu.test_z());
             }
@@ -437,6 +1784,36 @@ impl Control {
             IF_ODD => {
                 cond!(self, self.alu.test_o());
             }
+            IF_OVERFLOW => {
+                cond!(self, self.alu.test_v());
+            }
+            IF_NO_OVERFLOW => {
+                cond!(self, !self.alu.test_v());
+            }
+            IF_GREATER_EQUAL => {
+                cond!(self, self.alu.test_n() == self.alu.test_v());
+            }
+            IF_LESS_EQUAL => {
+                cond!(
+                    self,
+                    self.alu.test_z() || self.alu.test_n() != self.alu.test_v()
+                );
+            }
+            IF_GREATER => {
+                cond!(
+                    self,
+                    !self.alu.test_z() && self.alu.test_n() == self.alu.test_v()
+                );
+            }
+            IF_LESS => {
+                cond!(self, self.alu.test_n() != self.alu.test_v());
+            }
+            IF_HIGHER => {
+                cond!(self, !self.alu.test_c() && !self.alu.test_z());
+            }
+            IF_LOWER => {
+                cond!(self, self.alu.test_c());
+            }
             IF_CARRY => {
                 cond!(self, self.alu.test_c());
             }
@@ -446,6 +1823,10 @@ impl Control {
 
             SET_STACK => {
                 self.stack_ptr = Wrapping(param_16);
+                self.stack_base = param_16;
+            }
+            SET_LIMIT => {
+                self.stack_limit = Some(param_16);
             }
 
             GOTO => {
@@ -454,70 +1835,70 @@ impl Control {
 
             IMM_LOAD => {
                 self.mem.set_addr(param_16);
-                let x = self.mem.read();
+                let x = self.mem.read()?;
                 push!(self, x);
             }
             IMM_LOAD_OFFSET_B => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let offset = self.mem.read();
+                let offset = self.mem.read()?;
                 let final_addr = (Wrapping(param_16) + Wrapping(offset as u16)).0;
                 self.mem.set_addr(final_addr);
-                let x = self.mem.read();
+                let x = self.mem.read()?;
 
                 self.mem.set_addr(self.stack_ptr.0);
-                self.mem.write(x);
+                self.mem.write(x)?;
             }
             LOAD => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let addr_high = self.mem.read();
+                let addr_high = self.mem.read()?;
                 self.stack_ptr -= Wrapping(1);
                 self.mem.set_addr(self.stack_ptr.0);
-                let addr_low = self.mem.read();
+                let addr_low = self.mem.read()?;
 
                 let addr: u16 = (addr_high as u16) << 8 | (addr_low as u16);
 
                 self.mem.set_addr(addr);
-                let x = self.mem.read();
+                let x = self.mem.read()?;
 
                 self.mem.set_addr(self.stack_ptr.0);
-                self.mem.write(x);
+                self.mem.write(x)?;
             }
 
             IMM_SAVE => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let x = self.mem.read();
+                let x = self.mem.read()?;
                 self.stack_ptr -= Wrapping(1);
                 self.mem.set_addr(param_16);
-                self.mem.write(x);
+                self.mem.write(x)?;
             }
             IMM_SAVE_OFFSET_B => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let offset = self.mem.read();
+                let offset = self.mem.read()?;
                 let final_addr = (Wrapping(param_16) + Wrapping(offset as u16)).0;
                 self.stack_ptr -= Wrapping(1);
                 self.mem.set_addr(self.stack_ptr.0);
-                let x = self.mem.read();
+                let x = self.mem.read()?;
                 self.stack_ptr -= Wrapping(1);
 
                 self.mem.set_addr(final_addr);
-                self.mem.write(x);
+                self.mem.write(x)?;
             }
             SAVE => {
                 self.mem.set_addr(self.stack_ptr.0);
-                let addr_high = self.mem.read();
+                let addr_high = self.mem.read()?;
                 self.stack_ptr -= Wrapping(1);
                 self.mem.set_addr(self.stack_ptr.0);
-                let addr_low = self.mem.read();
+                let addr_low = self.mem.read()?;
                 self.stack_ptr -= Wrapping(1);
 
                 let addr: u16 = (addr_high as u16) << 8 | (addr_low as u16);
 
                 self.mem.set_addr(self.stack_ptr.0);
-                let x = self.mem.read();
+                let x = self.mem.read()?;
                 self.stack_ptr -= Wrapping(1);
 
                 self.mem.set_addr(addr);
-                self.mem.write(x);
+                self.mem.write(x)?;
             }
 
             LOCAL => {
@@ -538,126 +1919,22 @@ impl Control {
 
             _ => self.running = false,
         }
+
+        Ok(())
     }
 }
 
-pub fn debug_print(image: &Vec<u8>) {
+/// Prints `image` as a hex dump, sixteen bytes per row.
+pub fn debug_print(image: &[u8]) {
     print!("     0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F");
 
-    let mut address: u16 = 0;
-    let mut count: u8 = 16;
+    for (row, line) in image.chunks(16).enumerate() {
+        println!();
+        print!("{:03X} ", row & 0xFFF);
 
-    for x in image {
-        if count == 16 {
-            count = 0;
-            println!();
-            print!("{:03X} ", address >> 4 & 0xFFF);
+        for x in line {
+            print!("{:02X} ", x);
         }
-
-        print!("{:02X} ", x);
-        count += 1;
-        address += 1;
     }
     println!();
 }
-
-struct ControlRace(UnsafeCell<Control>);
-
-unsafe impl Sync for ControlRace {}
-impl ControlRace {
-    fn new(v: Control) -> ControlRace {
-        return ControlRace(UnsafeCell::new(v));
-    }
-
-    unsafe fn get(&self) -> *mut Control {
-        return self.0.get();
-    }
-}
-
-pub fn test_pgm() {
-    let program: Vec<u8> = vec![
-        // initialize
-        SET_STACK,
-        0x00,
-        0x01,
-        CONST_3,
-        SAVE_0,
-        // START of program
-        ENTER,
-        IMM_CONST,
-        48,
-        IMM_CONST,
-        16,
-        IMM_CONST_D,
-        0x10,
-        0x00, // SUM_EQUALS_64
-        CALL,
-        LEAVE,
-        WAIT,
-        // subroutine SUM_EQUALS_64
-        LOCAL_1,
-        LOCAL_0,
-        ADD,
-        LOCAL_2,
-        IMM_CONST,
-        64,
-        COMPARE,
-        IF_EQUAL,
-        IMM_BRANCH,
-        2,
-        IMM_BRANCH,
-        3,
-        CONST_1,
-        SAVE_0,
-        GOBACK,
-        CONST_0,
-        SAVE_0,
-        GOBACK,
-    ];
-
-    debug_print(&program);
-    println!();
-
-    let mut image: Vec<u8> = vec![0; 512];
-
-    let mut index = 0;
-    for x in program {
-        image[index] = x;
-        index += 1;
-    }
-
-    let mut control = new();
-    control.load_image(image);
-
-    control.start();
-
-    let ptr = Arc::new(ControlRace::new(control));
-    let cln1 = ptr.clone();
-    let cln2 = ptr.clone();
-
-    unsafe {
-        let exec = thread::spawn(move || {
-            let control = (*cln1).get();
-            while (*control).is_running() {
-                (*control).execute_instruction();
-            }
-        });
-
-        let monitor = thread::spawn(move || {
-            let control = (*cln2).get();
-            loop {
-                if (*control).mem.public_read(175) == 1 {
-                    for addr in 176..255 {
-                        let ch = (*control).mem.public_read(addr);
-                        if 32 <= ch && ch <= 126 {}
-                    }
-                }
-            }
-        });
-
-        exec.join().unwrap();
-
-        let control = ptr.clone().get();
-        (*control).view();
-    }
-}
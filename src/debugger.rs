@@ -0,0 +1,673 @@
+//! Command interpreter behind the `stack85-dbg` REPL.
+//!
+//! Every command returns the text to show, or an error message. Addresses
+//! may be given as numbers (`0x1F`, `31`), labels, or a label plus or minus
+//! a number.
+
+use crate::asm::Assembly;
+use crate::control::{self, Control};
+use crate::disasm::{self, Instruction};
+use crate::memory::{MemWrite, WatchKind};
+use crate::savestate;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::thread;
+use std::time::Duration;
+
+pub const HELP: &str = "\
+step [n]                      s  execute n instructions (default 1)
+next [n]                      n  like step, but run a CALL until it returns
+continue [n]                  c  run until a breakpoint, stop or idle WAIT,
+                                 at most n steps
+back [n]                     rs  undo the last n steps (default 1)
+rc                               run backwards to a breakpoint or watched write
+break [addr]                  b  set a breakpoint, or list them
+delete <addr>                 d  remove a breakpoint
+watch [<r|w|c> <addr> [len]]     stop after a read, write or change, or list
+unwatch <r|w|c> <addr> [len]     remove a watchpoint
+regs                          r  show the registers
+set <reg> <value>                set ip, sp, ln, lo, s0-s3, flags, im, sb or
+                                 sl (stack base and limit; sl off unchecks)
+x <addr> [len]                   examine memory
+deposit <addr> <byte>...    dep  write bytes to memory
+bank [n]                         select bank n, or show the banks
+stack [n]                        show the top n bytes of the stack
+dis [addr] [n]                l  disassemble around IP or from addr
+irq <line>                       raise an interrupt line
+start                            start the machine after a halt or fault
+save <file>                      write a save state
+restore <file>                   load a save state
+help                          h  show this text
+quit                          q  leave the debugger";
+
+/// The number of steps the debugger can undo, unless told otherwise.
+pub const DEFAULT_HISTORY: usize = 100_000;
+
+// how far back `dis` looks for an instruction boundary before IP
+const DIS_LOOKBACK: u16 = 12;
+
+// how many milliseconds a run waits for an interrupt before handing back
+// to the user, who can raise one with `irq`
+const WAIT_PATIENCE: u32 = 100;
+
+// Why `run_until` handed control back early.
+enum Stop {
+    Breakpoint(u16),
+    Waiting(u16),
+}
+
+pub struct Debugger {
+    control: Control,
+    assembly: Assembly,
+    breakpoints: BTreeSet<u16>,
+}
+
+/// Creates a debugger for `control`, naming addresses after the labels of
+/// `assembly`.
+pub fn new(control: Control, assembly: Assembly) -> Debugger {
+    Debugger {
+        control,
+        assembly,
+        breakpoints: BTreeSet::new(),
+    }
+}
+
+impl Debugger {
+    pub fn control(&self) -> &Control {
+        &self.control
+    }
+
+    pub fn control_mut(&mut self) -> &mut Control {
+        &mut self.control
+    }
+
+    /// Runs one command line. Blank lines do nothing.
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(String::new()),
+        };
+
+        match name {
+            "s" | "step" => self.step(count(args.first(), 1)?),
+            "n" | "next" => self.next(count(args.first(), 1)?),
+            "c" | "continue" => {
+                let limit = match args.first() {
+                    Some(n) => Some(count(Some(n), 1)?),
+                    None => None,
+                };
+                self.resume(limit)
+            }
+            "rs" | "back" => self.back(count(args.first(), 1)?),
+            "rc" => Ok(self.reverse_continue()),
+            "b" | "break" => match args.first() {
+                Some(addr) => {
+                    let addr = self.address(addr)?;
+                    self.breakpoints.insert(addr);
+                    Ok(format!("breakpoint at {}", self.describe(addr)))
+                }
+                None => Ok(self.list_breakpoints()),
+            },
+            "d" | "delete" => {
+                let addr = self.address(args.first().ok_or("delete needs an address")?)?;
+                if self.breakpoints.remove(&addr) {
+                    Ok(format!("deleted breakpoint at {}", self.describe(addr)))
+                } else {
+                    Err(format!("no breakpoint at {}", self.describe(addr)))
+                }
+            }
+            "watch" if args.is_empty() => Ok(self.list_watchpoints()),
+            "watch" | "unwatch" => {
+                let (kind, start, end) = self.watch_args(args)?;
+                if name == "watch" {
+                    self.control.watch(start, end, kind);
+                    Ok(format!("watching {}", describe_watch(start, end, kind)))
+                } else if self.control.unwatch(start, end, kind) {
+                    Ok(format!("removed {}", describe_watch(start, end, kind)))
+                } else {
+                    Err(format!("not watching {}", describe_watch(start, end, kind)))
+                }
+            }
+            "r" | "regs" => Ok(self.registers()),
+            "set" => match args {
+                [register, value] => self.set(register, value),
+                _ => Err("usage: set <reg> <value>".to_string()),
+            },
+            "x" => {
+                let addr = self.address(args.first().ok_or("x needs an address")?)?;
+                Ok(self.examine(addr, count(args.get(1), 16)?))
+            }
+            "dep" | "deposit" => match args.split_first() {
+                Some((addr, bytes)) if !bytes.is_empty() => {
+                    let addr = self.address(addr)?;
+                    self.deposit(addr, bytes)
+                }
+                _ => Err("usage: deposit <addr> <byte>...".to_string()),
+            },
+            "bank" => self.bank(args.first()),
+            "stack" => Ok(self.stack(count(args.first(), 8)?)),
+            "l" | "dis" => match args.first() {
+                Some(addr) => {
+                    let addr = self.address(addr)?;
+                    Ok(self.disassemble(addr, count(args.get(1), 8)?))
+                }
+                None => Ok(self.around_ip(8)),
+            },
+            "irq" => {
+                let line = number(args.first().ok_or("irq needs a line")?)?;
+                if line >= control::IRQ_LINES as u32 {
+                    return Err(format!("there are {} lines", control::IRQ_LINES));
+                }
+                self.control.raise_irq(line as u8);
+                Ok(format!("raised line {}", line))
+            }
+            "save" => {
+                let path = args.first().ok_or("save needs a file")?;
+                savestate::save(&self.control, path).map_err(|e| format!("{}: {}", path, e))?;
+                Ok(format!("saved to {}", path))
+            }
+            "restore" => {
+                let path = args.first().ok_or("restore needs a file")?;
+                savestate::load(&mut self.control, path).map_err(|e| format!("{}: {}", path, e))?;
+                Ok(self.current())
+            }
+            "start" => {
+                self.control.start();
+                Ok(self.current())
+            }
+            "h" | "help" => Ok(HELP.to_string()),
+            _ => Err(format!("unknown command `{}`; try `help`", name)),
+        }
+    }
+
+    /// Parses a number, label, or label plus or minus a number.
+    pub fn address(&self, text: &str) -> Result<u16, String> {
+        let split = text.rfind(['+', '-']).filter(|&i| i > 0);
+        let (base, offset) = match split {
+            Some(i) => {
+                let offset = number(&text[i + 1..])? as i64;
+                (
+                    &text[..i],
+                    if &text[i..=i] == "-" { -offset } else { offset },
+                )
+            }
+            None => (text, 0),
+        };
+
+        let base = match self.assembly.label(base) {
+            Some(addr) => addr as i64,
+            None => number(base).map_err(|_| format!("unknown label `{}`", base))? as i64,
+        };
+
+        match base + offset {
+            addr @ 0..=0xFFFF => Ok(addr as u16),
+            _ => Err(format!("`{}` is outside the address space", text)),
+        }
+    }
+
+    fn describe(&self, addr: u16) -> String {
+        match self.assembly.label_at(addr) {
+            Some(label) => format!("{:04X} <{}>", addr, label),
+            None => format!("{:04X}", addr),
+        }
+    }
+
+    fn decode(&self, addr: u16) -> Instruction {
+        let memory = self.control.memory();
+        disasm::decode_with(|a| memory.public_read(a).ok(), addr)
+    }
+
+    fn listing(&self, instruction: &Instruction) -> String {
+        let mut line = String::new();
+        if let Some(label) = self.assembly.label_at(instruction.addr) {
+            let _ = writeln!(line, "{}:", label);
+        }
+
+        let marker = if self.breakpoints.contains(&instruction.addr) {
+            '*'
+        } else {
+            ' '
+        };
+        let pointer = if instruction.addr == self.control.instr_ptr() {
+            '>'
+        } else {
+            ' '
+        };
+        let _ = write!(
+            line,
+            "{}{} {}",
+            marker,
+            pointer,
+            instruction.listing(&self.assembly)
+        );
+        line
+    }
+
+    /// The instruction at IP, or why the machine is stopped.
+    fn current(&self) -> String {
+        let instruction = self.decode(self.control.instr_ptr());
+        let line = self.listing(&instruction);
+
+        if let Some(fault) = self.control.fault() {
+            format!("fault: {}\n{}", fault, line)
+        } else if let Some(stop) = self.control.watch_stop() {
+            let by = self.decode(stop.ip).listing(&self.assembly);
+            format!("watchpoint: {}\nby {}\n{}", stop.hit, by, line)
+        } else if self.control.is_waiting() {
+            format!("waiting for an interrupt\n{}", line)
+        } else if !self.control.is_running() {
+            format!("halted\n{}", line)
+        } else {
+            line
+        }
+    }
+
+    // a watchpoint stop is only a pause, so carry on from it
+    fn leave_watch_stop(&mut self) {
+        if self.control.watch_stop().is_some() {
+            self.control.start();
+        }
+    }
+
+    fn step(&mut self, n: u32) -> Result<String, String> {
+        self.leave_watch_stop();
+        for _ in 0..n {
+            if !self.control.is_running() {
+                break;
+            }
+            self.control.step();
+        }
+        Ok(self.current())
+    }
+
+    fn next(&mut self, n: u32) -> Result<String, String> {
+        self.leave_watch_stop();
+        for _ in 0..n {
+            if !self.control.is_running() {
+                break;
+            }
+
+            let instruction = self.decode(self.control.instr_ptr());
+            self.control.step();
+
+            if instruction.opcode() == control::CALL {
+                // run the subroutine until it comes back
+                let stop = self.run_until(None, |c| c.instr_ptr() == instruction.next());
+                if let Some(stop) = stop {
+                    return Ok(self.report(stop));
+                }
+            }
+        }
+        Ok(self.current())
+    }
+
+    fn resume(&mut self, limit: Option<u32>) -> Result<String, String> {
+        self.leave_watch_stop();
+        if !self.control.is_running() && !self.control.is_waiting() {
+            return Ok(self.current());
+        }
+
+        // leave a breakpoint we are sitting on before checking for others
+        self.control.step();
+        let limit = limit.map(|n| n - 1);
+        match self.run_until(limit, |_| false) {
+            Some(stop) => Ok(self.report(stop)),
+            None => Ok(self.current()),
+        }
+    }
+
+    fn report(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Breakpoint(addr) => {
+                format!("breakpoint at {}\n{}", self.describe(addr), self.current())
+            }
+            Stop::Waiting(addr) => format!(
+                "waiting for interrupt at {}\n{}",
+                self.describe(addr),
+                self.current()
+            ),
+        }
+    }
+
+    // Runs until `done` holds, a breakpoint or a long wait for an interrupt
+    // stops it (returned), the limit runs out or the machine stops without
+    // an enabled interrupt to wake it. Each millisecond spent waiting counts
+    // as a step against the limit.
+    fn run_until(&mut self, limit: Option<u32>, done: impl Fn(&Control) -> bool) -> Option<Stop> {
+        let mut executed = 0;
+        let mut waited = 0;
+
+        loop {
+            if done(&self.control) || limit.is_some_and(|n| executed >= n) {
+                return None;
+            }
+
+            if self.control.is_waiting() && self.control.int_mask() != 0 {
+                if waited == WAIT_PATIENCE {
+                    return Some(Stop::Waiting(self.control.instr_ptr()));
+                }
+                thread::sleep(Duration::from_millis(1));
+                self.control.tick();
+                executed += 1;
+                waited += 1;
+                continue;
+            }
+            waited = 0;
+            if !self.control.is_running() {
+                return None;
+            }
+
+            let ip = self.control.instr_ptr();
+            if self.breakpoints.contains(&ip) {
+                return Some(Stop::Breakpoint(ip));
+            }
+
+            self.control.step();
+            executed += 1;
+        }
+    }
+
+    fn back(&mut self, n: u32) -> Result<String, String> {
+        for _ in 0..n {
+            if self.control.step_back().is_none() {
+                return Ok(format!("start of history\n{}", self.current()));
+            }
+        }
+        Ok(self.current())
+    }
+
+    fn reverse_continue(&mut self) -> String {
+        loop {
+            let writes = match self.control.step_back() {
+                Some(writes) => writes,
+                None => return format!("start of history\n{}", self.current()),
+            };
+
+            let ip = self.control.instr_ptr();
+            if self.breakpoints.contains(&ip) {
+                return format!("breakpoint at {}\n{}", self.describe(ip), self.current());
+            }
+
+            let watched = |w: &MemWrite| {
+                self.control.watchpoints().iter().any(|p| {
+                    p.start <= w.addr
+                        && w.addr <= p.end
+                        && (p.kind == WatchKind::Write
+                            || p.kind == WatchKind::Change && w.old != w.new)
+                })
+            };
+            if let Some(write) = writes.iter().find(|w| watched(w)) {
+                return format!(
+                    "watchpoint: write {:04X}: {:02X} -> {:02X}\n{}",
+                    write.addr,
+                    write.old,
+                    write.new,
+                    self.current()
+                );
+            }
+        }
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "no breakpoints".to_string();
+        }
+
+        let lines: Vec<String> = self
+            .breakpoints
+            .iter()
+            .map(|&addr| self.describe(addr))
+            .collect();
+        lines.join("\n")
+    }
+
+    fn watch_args(&self, args: &[&str]) -> Result<(WatchKind, u16, u16), String> {
+        let (kind, addr, len) = match args {
+            [kind, addr] => (kind, addr, 1),
+            [kind, addr, len] => (kind, addr, count(Some(len), 1)?),
+            _ => return Err("usage: watch <r|w|c> <addr> [len]".to_string()),
+        };
+
+        let kind = match *kind {
+            "r" => WatchKind::Read,
+            "w" => WatchKind::Write,
+            "c" => WatchKind::Change,
+            _ => return Err(format!("unknown watch kind `{}`", kind)),
+        };
+        let start = self.address(addr)?;
+        match (start as u32).checked_add(len - 1) {
+            Some(end) if end <= 0xFFFF => Ok((kind, start, end as u16)),
+            _ => Err("watched range wraps around".to_string()),
+        }
+    }
+
+    fn list_watchpoints(&self) -> String {
+        if self.control.watchpoints().is_empty() {
+            return "no watchpoints".to_string();
+        }
+
+        let lines: Vec<String> = self
+            .control
+            .watchpoints()
+            .iter()
+            .map(|w| describe_watch(w.start, w.end, w.kind))
+            .collect();
+        lines.join("\n")
+    }
+
+    fn registers(&self) -> String {
+        let mut text = self.control.registers().to_string();
+        let _ = write!(
+            text,
+            "\nIM:   {:02X} IR:   {:02X} VB: {:04X}",
+            self.control.int_mask(),
+            self.control.pending_irqs(),
+            self.control.vector_base()
+        );
+        if self.control.memory().banking().is_some() {
+            let _ = write!(text, " BK: {:02X}", self.control.memory().bank());
+        }
+        if let Some(limit) = self.control.stack_limit() {
+            let _ = write!(
+                text,
+                "\nSB: {:04X} SL: {:04X}",
+                self.control.stack_base(),
+                limit
+            );
+        }
+        text
+    }
+
+    fn bank(&mut self, bank: Option<&&str>) -> Result<String, String> {
+        let banking = self
+            .control
+            .memory()
+            .banking()
+            .ok_or("memory is not banked")?;
+
+        if let Some(bank) = bank {
+            let bank = number(bank)?;
+            if bank > 0xFF || !self.control.memory_mut().select_bank(bank as u8) {
+                return Err(format!("there are {} banks", banking.count));
+            }
+        }
+        Ok(format!(
+            "bank {} of {} at {:04X}-{:04X}, register {:04X}",
+            self.control.memory().bank(),
+            banking.count,
+            banking.start,
+            banking.end,
+            banking.register
+        ))
+    }
+
+    fn set(&mut self, register: &str, value: &str) -> Result<String, String> {
+        if register == "sl" && value == "off" {
+            self.control.set_stack_limit(None);
+            return Ok(self.registers());
+        }
+        let value = match register {
+            "ip" | "sp" | "ln" | "lo" | "sb" | "sl" => self.address(value)? as u32,
+            _ => number(value)?,
+        };
+
+        let byte = || {
+            if value <= 0xFF {
+                Ok(value as u8)
+            } else {
+                Err(format!("{} does not fit in a byte", value))
+            }
+        };
+
+        match register {
+            "ip" => self.control.set_instr_ptr(value as u16),
+            "sp" => self.control.set_stack_ptr(value as u16),
+            "ln" => self.control.set_link(value as u16),
+            "lo" => self.control.set_local(value as u16),
+            "s0" => self.control.set_save_0(byte()?),
+            "s1" => self.control.set_save_1(byte()?),
+            "s2" => self.control.set_save_2(byte()?),
+            "s3" => self.control.set_save_3(byte()?),
+            "flags" => self.control.set_flags(byte()?),
+            "im" => self.control.set_int_mask(byte()?),
+            "sb" => self.control.set_stack_base(value as u16),
+            "sl" => self.control.set_stack_limit(Some(value as u16)),
+            _ => return Err(format!("unknown register `{}`", register)),
+        }
+        Ok(self.registers())
+    }
+
+    fn examine(&self, addr: u16, len: u32) -> String {
+        let memory = self.control.memory();
+        let mut lines = Vec::new();
+        let mut row = String::new();
+
+        for i in 0..len {
+            let a = addr.wrapping_add(i as u16);
+            if i % 16 == 0 {
+                if !row.is_empty() {
+                    lines.push(row);
+                }
+                row = format!("{:04X} ", a);
+            }
+            match memory.public_read(a) {
+                Ok(b) => {
+                    let _ = write!(row, " {:02X}", b);
+                }
+                Err(_) => row.push_str(" --"),
+            }
+        }
+        if !row.is_empty() {
+            lines.push(row);
+        }
+        lines.join("\n")
+    }
+
+    fn deposit(&mut self, addr: u16, bytes: &[&str]) -> Result<String, String> {
+        let bytes = bytes
+            .iter()
+            .map(|b| match number(b)? {
+                v @ 0..=0xFF => Ok(v as u8),
+                v => Err(format!("{} does not fit in a byte", v)),
+            })
+            .collect::<Result<Vec<u8>, String>>()?;
+
+        for (i, &b) in bytes.iter().enumerate() {
+            self.control
+                .memory_mut()
+                .public_write(b, addr.wrapping_add(i as u16))
+                .map_err(|fault| fault.to_string())?;
+        }
+        Ok(self.examine(addr, bytes.len() as u32))
+    }
+
+    fn stack(&self, n: u32) -> String {
+        let memory = self.control.memory();
+        let sp = self.control.stack_ptr();
+
+        let lines: Vec<String> = (0..n)
+            .map(|i| {
+                let a = sp.wrapping_sub(i as u16);
+                let pointer = if i == 0 { "SP>" } else { "   " };
+                match memory.public_read(a) {
+                    Ok(b) => format!("{} {:04X}  {:02X}", pointer, a, b),
+                    Err(_) => format!("{} {:04X}  --", pointer, a),
+                }
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    fn disassemble(&self, addr: u16, n: u32) -> String {
+        let mut lines = Vec::new();
+        let mut addr = addr;
+
+        for _ in 0..n {
+            let instruction = self.decode(addr);
+            lines.push(self.listing(&instruction));
+            addr = instruction.next();
+        }
+        lines.join("\n")
+    }
+
+    // Instruction boundaries before IP are unknown, so start from the
+    // furthest point back that decodes to an instruction starting at IP.
+    fn around_ip(&self, n: u32) -> String {
+        let ip = self.control.instr_ptr();
+        let start = (1..=DIS_LOOKBACK.min(ip))
+            .rev()
+            .map(|back| ip - back)
+            .find(|&start| {
+                let mut addr = start;
+                while addr < ip {
+                    addr = self.decode(addr).next();
+                }
+                addr == ip
+            })
+            .unwrap_or(ip);
+
+        let before = {
+            let mut count: u32 = 0;
+            let mut addr = start;
+            while addr < ip {
+                addr = self.decode(addr).next();
+                count += 1;
+            }
+            count
+        };
+
+        // show at most three instructions before IP
+        let mut addr = start;
+        for _ in 0..before.saturating_sub(3) {
+            addr = self.decode(addr).next();
+        }
+        self.disassemble(addr, n + before.min(3))
+    }
+}
+
+fn describe_watch(start: u16, end: u16, kind: WatchKind) -> String {
+    if start == end {
+        format!("{} {:04X}", kind, start)
+    } else {
+        format!("{} {:04X}-{:04X}", kind, start, end)
+    }
+}
+
+fn number(text: &str) -> Result<u32, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("bad number `{}`", text))
+}
+
+fn count(text: Option<&&str>, default: u32) -> Result<u32, String> {
+    match text {
+        Some(text) => match number(text)? {
+            0 => Err("count must be at least 1".to_string()),
+            n => Ok(n),
+        },
+        None => Ok(default),
+    }
+}
@@ -0,0 +1,22 @@
+//! Memory-mapped peripherals.
+
+/// A peripheral mapped into a range of the address space.
+///
+/// Offsets are relative to the start of the range the device is mapped at.
+/// Devices are called synchronously by the control unit, so a read or write
+/// by the program is seen before the next instruction executes.
+pub trait Device: Send {
+    /// Reads a register on behalf of the program; may have side effects.
+    fn read(&mut self, offset: u16) -> u8;
+
+    fn write(&mut self, offset: u16, value: u8);
+
+    /// Reads a register without side effects, on behalf of the host.
+    fn peek(&self, offset: u16) -> u8;
+
+    /// Called once per instruction and while the machine waits; returns the
+    /// interrupt lines to raise, one bit per line.
+    fn tick(&mut self) -> u8 {
+        0
+    }
+}
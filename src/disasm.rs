@@ -0,0 +1,172 @@
+//! Disassembler for stack85 images.
+//!
+//! Instructions are decoded with the length encoded in the top two bits of
+//! each opcode, exactly as `Control::execute_instruction` advances the
+//! instruction pointer, so unknown opcodes do not desynchronise the walk.
+//! The text form of an instruction is valid assembler input.
+
+use crate::asm::Assembly;
+use crate::control;
+use std::fmt;
+use std::num::Wrapping;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    None,
+    Byte(u8),
+    Word(u16),
+    Address(u16),
+    Offset { offset: i16, target: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: Option<&'static str>,
+    pub operand: Operand,
+    // for IF_* instructions, the bytes skipped when the condition is false
+    pub skip: Option<u16>,
+    pub truncated: bool,
+}
+
+/// Decodes the instruction at `addr`; bytes beyond the end of `image` read
+/// as zero and mark the instruction as truncated.
+pub fn decode(image: &[u8], addr: u16) -> Instruction {
+    decode_with(|a| image.get(a as usize).copied(), addr)
+}
+
+/// Decodes the instruction at `addr`, reading bytes with `read`; bytes it
+/// cannot read are zero and mark the instruction as truncated.
+pub fn decode_with(read: impl Fn(u16) -> Option<u8>, addr: u16) -> Instruction {
+    let fetch = |offset: u16| read((Wrapping(addr) + Wrapping(offset)).0);
+
+    let opcode = fetch(0).unwrap_or(0);
+    let length = control::instruction_length(opcode);
+    let bytes: Vec<u8> = (0..length).map(|i| fetch(i).unwrap_or(0)).collect();
+    let truncated = (0..length).any(|i| fetch(i).is_none());
+    let next = (Wrapping(addr) + Wrapping(length)).0;
+
+    let operand = match (opcode, length) {
+        (control::IMM_BRANCH, _) => Operand::Offset {
+            offset: bytes[1] as i16,
+            target: (Wrapping(next) + Wrapping(bytes[1] as u16)).0,
+        },
+        (control::IMM_BRANCH_S, _) => Operand::Offset {
+            offset: bytes[1] as i8 as i16,
+            target: (Wrapping(next) + Wrapping(bytes[1] as i8 as u16)).0,
+        },
+        (control::GOTO, _)
+        | (control::SET_VECTORS, _)
+        | (control::IMM_LOAD, _)
+        | (control::IMM_LOAD_OFFSET_B, _)
+        | (control::IMM_SAVE, _)
+        | (control::IMM_SAVE_OFFSET_B, _) => {
+            Operand::Address(u16::from_le_bytes([bytes[1], bytes[2]]))
+        }
+        _ if !control::takes_operand(opcode) => Operand::None,
+        (_, 2) => Operand::Byte(bytes[1]),
+        _ => Operand::Word(u16::from_le_bytes([bytes[1], bytes[2]])),
+    };
+
+    // cond! skips the whole of the following instruction
+    let skip = if (control::IF_EQUAL..=control::IF_NO_CARRY).contains(&opcode) {
+        Some(control::instruction_length(fetch(1).unwrap_or(0)))
+    } else {
+        None
+    };
+
+    Instruction {
+        addr,
+        bytes,
+        mnemonic: control::mnemonic(opcode),
+        operand,
+        skip,
+        truncated,
+    }
+}
+
+/// Decodes every instruction starting in `start..end`.
+pub fn disassemble(image: &[u8], start: u16, end: u32) -> Vec<Instruction> {
+    let mut listing = Vec::new();
+    let mut addr = start as u32;
+
+    while addr < end && addr <= 0xFFFF {
+        let instruction = decode(image, addr as u16);
+        addr += instruction.length() as u32;
+        listing.push(instruction);
+    }
+    listing
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    /// The address of the instruction that follows this one.
+    pub fn next(&self) -> u16 {
+        (Wrapping(self.addr) + Wrapping(self.length())).0
+    }
+
+    /// Formats the instruction as assembler text, naming addresses after
+    /// the labels of `assembly` where possible.
+    pub fn text(&self, assembly: &Assembly) -> String {
+        let name = |addr: u16| assembly.label_at(addr);
+
+        let mnemonic = match self.mnemonic {
+            Some(mnemonic) => mnemonic,
+            None => {
+                let bytes: Vec<String> =
+                    self.bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+                return format!(".byte {} ; unknown opcode", bytes.join(", "));
+            }
+        };
+
+        let mut text = match self.operand {
+            Operand::None => mnemonic.to_string(),
+            Operand::Byte(b) => format!("{} 0x{:02X}", mnemonic, b),
+            Operand::Word(w) => format!("{} 0x{:04X}", mnemonic, w),
+            Operand::Address(a) => match name(a) {
+                Some(label) => format!("{} {}", mnemonic, label),
+                None => format!("{} 0x{:04X}", mnemonic, a),
+            },
+            Operand::Offset { offset, target } => match name(target) {
+                Some(label) => format!("{} {}", mnemonic, label),
+                None => format!("{} {} ; -> 0x{:04X}", mnemonic, offset, target),
+            },
+        };
+
+        if let Some(skip) = self.skip {
+            let target = (Wrapping(self.next()) + Wrapping(skip)).0;
+            text += &format!(" ; if false skip {} to 0x{:04X}", skip, target);
+        }
+
+        if self.truncated {
+            text += " ; truncated";
+        }
+        text
+    }
+
+    /// Formats the instruction as a listing line: address, raw bytes and
+    /// assembler text.
+    pub fn listing(&self, assembly: &Assembly) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(
+            "{:04X}  {:<9} {}",
+            self.addr,
+            bytes.join(" "),
+            self.text(assembly)
+        )
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.listing(&Assembly::default()))
+    }
+}
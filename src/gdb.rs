@@ -0,0 +1,563 @@
+//! A GDB remote serial protocol stub.
+//!
+//! Serves one debugger connection at a time over any byte stream. The
+//! registers are described to GDB with a target description, in the order
+//! ip, sp, ln, lo, s0-s3, flags, each sent low byte first. Software
+//! breakpoints are kept by the stub rather than patched into memory, and
+//! watchpoints map onto those of `Control`.
+//!
+//! A machine that halts reports SIGTRAP rather than exiting, so its final
+//! state can still be inspected; a fault reports SIGSEGV. Reverse step and
+//! continue use the control unit's history, if it keeps one.
+
+use crate::control::{Control, Registers, WatchStop};
+use crate::memory::{WatchHit, WatchKind};
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+// instructions executed between checks for an interrupt from GDB
+const BATCH: usize = 1024;
+
+// how often devices are ticked while the machine waits for an interrupt
+const WAIT_TICK: Duration = Duration::from_millis(1);
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.stack85.core">
+    <reg name="ip" bitsize="16" type="code_ptr" regnum="0"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="ln" bitsize="16" type="code_ptr"/>
+    <reg name="lo" bitsize="16" type="data_ptr"/>
+    <reg name="s0" bitsize="8" type="uint8"/>
+    <reg name="s1" bitsize="8" type="uint8"/>
+    <reg name="s2" bitsize="8" type="uint8"/>
+    <reg name="s3" bitsize="8" type="uint8"/>
+    <reg name="flags" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+// register sizes in bytes, in target description order
+const REGISTER_SIZES: [usize; 9] = [2, 2, 2, 2, 1, 1, 1, 1, 1];
+
+/// Accepts one GDB connection on `addr` and serves it until GDB detaches
+/// or kills the target, then returns the control unit.
+pub fn serve(control: Control, addr: impl ToSocketAddrs) -> io::Result<Control> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    session(control, stream.try_clone()?, stream)
+}
+
+/// Serves a connection that has already been made.
+pub fn session(
+    control: Control,
+    input: impl Read + Send + 'static,
+    output: impl Write,
+) -> io::Result<Control> {
+    let (sender, receiver) = mpsc::channel();
+
+    // read on a thread so that a running machine can notice GDB's interrupt
+    thread::spawn(move || {
+        let mut input = input;
+        let mut buf = [0; 256];
+        loop {
+            match input.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if buf[..n].iter().any(|&b| sender.send(b).is_err()) {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut stub = Stub {
+        control,
+        input: receiver,
+        output,
+        breakpoints: BTreeSet::new(),
+        ack: true,
+    };
+    stub.run()?;
+    Ok(stub.control)
+}
+
+enum Packet {
+    Data(String),
+    Interrupt,
+}
+
+enum Stop {
+    Signal(u8),
+    Breakpoint,
+    Watch(WatchStop),
+    // reverse execution ran out of history
+    HistoryBegin,
+}
+
+struct Stub<W: Write> {
+    control: Control,
+    input: Receiver<u8>,
+    output: W,
+    breakpoints: BTreeSet<u16>,
+    ack: bool,
+}
+
+impl<W: Write> Stub<W> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            let data = match packet {
+                Packet::Data(data) => data,
+                Packet::Interrupt => {
+                    let reply = self.stop_reply(Stop::Signal(SIGINT));
+                    self.send(&reply)?;
+                    continue;
+                }
+            };
+
+            match data.as_bytes().first() {
+                Some(b'k') => break,
+                Some(b'D') => {
+                    self.send("OK")?;
+                    break;
+                }
+                _ => {
+                    let reply = self.handle(&data);
+                    self.send(&reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Reads the next packet, acknowledging it; None once GDB hangs up.
+    fn receive(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            let byte = match self.input.recv() {
+                Ok(byte) => byte,
+                Err(_) => return Ok(None),
+            };
+
+            match byte {
+                0x03 => return Ok(Some(Packet::Interrupt)),
+                b'$' => {}
+                _ => continue, // acks, and noise between packets
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.input.recv() {
+                    Ok(b'#') => break,
+                    Ok(byte) => data.push(byte),
+                    Err(_) => return Ok(None),
+                }
+            }
+
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                match self.input.recv() {
+                    Ok(byte) => *digit = byte,
+                    Err(_) => return Ok(None),
+                }
+            }
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            let sum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+            if self.ack {
+                let reply = if expected == Some(sum) { b"+" } else { b"-" };
+                self.output.write_all(reply)?;
+                self.output.flush()?;
+            }
+            if expected == Some(sum) || !self.ack {
+                return Ok(Some(Packet::Data(String::from_utf8_lossy(&data).into())));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.output, "${}#{:02x}", data, sum)?;
+        self.output.flush()?;
+
+        // GDB resends on `-`; anything else is taken as an ack
+        while self.ack {
+            match self.input.recv_timeout(Duration::from_secs(1)) {
+                Ok(b'-') => {
+                    write!(self.output, "${}#{:02x}", data, sum)?;
+                    self.output.flush()?;
+                }
+                Ok(_) | Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, data: &str) -> String {
+        if !data.is_char_boundary(1) {
+            return String::new();
+        }
+
+        let (command, args) = data.split_at(1);
+        let reply = match command {
+            "?" => Some(self.stop_reply(Stop::Signal(SIGTRAP))),
+            "g" => Some(self.read_registers()),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "c" => {
+                let stop = self.resume(args);
+                Some(self.stop_reply(stop))
+            }
+            "s" => {
+                let stop = self.single_step(args);
+                Some(self.stop_reply(stop))
+            }
+            "b" if args == "s" => {
+                let stop = self.step_back();
+                Some(self.stop_reply(stop))
+            }
+            "b" if args == "c" => {
+                let stop = self.reverse_continue();
+                Some(self.stop_reply(stop))
+            }
+            "Z" => self.breakpoint(args, true),
+            "z" => self.breakpoint(args, false),
+            "H" => Some("OK".to_string()),
+            "q" => self.query(args),
+            "Q" if args == "StartNoAckMode" => {
+                self.ack = false;
+                Some("OK".to_string())
+            }
+            _ => None,
+        };
+
+        // an empty reply tells GDB the command is unsupported
+        reply.unwrap_or_default()
+    }
+
+    fn query(&self, args: &str) -> Option<String> {
+        if args.starts_with("Supported") {
+            return Some(
+                "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+;\
+                 ReverseStep+;ReverseContinue+"
+                    .to_string(),
+            );
+        }
+        if args == "Attached" {
+            return Some("1".to_string());
+        }
+
+        let range = args.strip_prefix("Xfer:features:read:target.xml:")?;
+        let (offset, len) = range.split_once(',')?;
+        let offset = usize::from_str_radix(offset, 16).ok()?;
+        let len = usize::from_str_radix(len, 16).ok()?;
+
+        let xml = TARGET_XML.as_bytes();
+        let start = offset.min(xml.len());
+        let end = (start + len).min(xml.len());
+        let marker = if end == xml.len() { 'l' } else { 'm' };
+        Some(format!(
+            "{}{}",
+            marker,
+            String::from_utf8_lossy(&xml[start..end])
+        ))
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Signal(signal) => format!("S{:02x}", signal),
+            Stop::Breakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::HistoryBegin => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            Stop::Watch(stop) => {
+                let kind = match stop.hit.kind {
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Write | WatchKind::Change => "watch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, stop.hit.addr)
+            }
+        }
+    }
+
+    // a watchpoint stop is only a pause, so carry on from it
+    fn leave_watch_stop(&mut self) {
+        if self.control.watch_stop().is_some() {
+            self.control.start();
+        }
+    }
+
+    fn stopped(&self) -> Option<Stop> {
+        if self.control.fault().is_some() {
+            Some(Stop::Signal(SIGSEGV))
+        } else if let Some(stop) = self.control.watch_stop() {
+            Some(Stop::Watch(stop))
+        } else if !self.control.is_running() {
+            Some(Stop::Signal(SIGTRAP))
+        } else {
+            None
+        }
+    }
+
+    fn jump(&mut self, args: &str) {
+        if let Ok(addr) = u16::from_str_radix(args, 16) {
+            self.control.set_instr_ptr(addr);
+        }
+    }
+
+    fn single_step(&mut self, args: &str) -> Stop {
+        self.jump(args);
+        self.leave_watch_stop();
+        self.control.step();
+        self.stopped().unwrap_or(Stop::Signal(SIGTRAP))
+    }
+
+    fn resume(&mut self, args: &str) -> Stop {
+        self.jump(args);
+        self.leave_watch_stop();
+
+        // leave a breakpoint we are sitting on before checking for others
+        let mut first = true;
+        loop {
+            if self.control.is_waiting() && self.control.int_mask() != 0 {
+                match self.input.recv_timeout(WAIT_TICK) {
+                    Ok(0x03) => return Stop::Signal(SIGINT),
+                    Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return Stop::Signal(SIGINT),
+                }
+                self.control.tick();
+                continue;
+            }
+
+            for _ in 0..BATCH {
+                if !first && self.breakpoints.contains(&self.control.instr_ptr()) {
+                    return Stop::Breakpoint;
+                }
+                first = false;
+
+                if !self.control.step() {
+                    break;
+                }
+            }
+
+            if let Some(stop) = self.stopped() {
+                if !(self.control.is_waiting() && self.control.int_mask() != 0) {
+                    return stop;
+                }
+            }
+
+            match self.input.try_recv() {
+                Ok(0x03) | Err(TryRecvError::Disconnected) => return Stop::Signal(SIGINT),
+                Ok(_) | Err(TryRecvError::Empty) => {}
+            }
+        }
+    }
+
+    fn step_back(&mut self) -> Stop {
+        match self.control.step_back() {
+            Some(_) => Stop::Signal(SIGTRAP),
+            None => Stop::HistoryBegin,
+        }
+    }
+
+    // runs backwards until a breakpoint, an undone write to a watched
+    // address (one that changed it, for a change watchpoint) or the start
+    // of the history
+    fn reverse_continue(&mut self) -> Stop {
+        loop {
+            let writes = match self.control.step_back() {
+                Some(writes) => writes,
+                None => return Stop::HistoryBegin,
+            };
+
+            if self.breakpoints.contains(&self.control.instr_ptr()) {
+                return Stop::Breakpoint;
+            }
+
+            let ip = self.control.instr_ptr();
+            for write in writes {
+                let watched = self.control.watchpoints().iter().find(|w| {
+                    w.start <= write.addr
+                        && write.addr <= w.end
+                        && (w.kind == WatchKind::Write
+                            || w.kind == WatchKind::Change && write.old != write.new)
+                });
+                if let Some(watch) = watched {
+                    return Stop::Watch(WatchStop {
+                        ip,
+                        hit: WatchHit {
+                            addr: write.addr,
+                            kind: watch.kind,
+                            old: write.old,
+                            new: write.new,
+                        },
+                    });
+                }
+            }
+
+            if let Ok(0x03) = self.input.try_recv() {
+                return Stop::Signal(SIGINT);
+            }
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let r = self.control.registers();
+        let mut text = String::new();
+        for value in [r.instr_ptr, r.stack_ptr, r.link, r.local] {
+            text += &hex(&value.to_le_bytes());
+        }
+        text += &hex(&r.save);
+        text += &hex(&[r.flags]);
+        text
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        let bytes = unhex(args)?;
+        if bytes.len() != REGISTER_SIZES.iter().sum::<usize>() {
+            return Some("E01".to_string());
+        }
+
+        let word = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        self.control.set_registers(&Registers {
+            instr_ptr: word(0),
+            stack_ptr: word(2),
+            link: word(4),
+            local: word(6),
+            save: [bytes[8], bytes[9], bytes[10], bytes[11]],
+            flags: bytes[12],
+        });
+        Some("OK".to_string())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let n = usize::from_str_radix(args, 16).ok()?;
+        let size = *REGISTER_SIZES.get(n)?;
+        let offset: usize = REGISTER_SIZES[..n].iter().sum();
+        let all = self.read_registers();
+        Some(all[offset * 2..(offset + size) * 2].to_string())
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (n, value) = args.split_once('=')?;
+        let n = usize::from_str_radix(n, 16).ok()?;
+        let value = unhex(value)?;
+        if REGISTER_SIZES.get(n) != Some(&value.len()) {
+            return Some("E01".to_string());
+        }
+
+        let mut r = self.control.registers();
+        let word = || u16::from_le_bytes([value[0], value[1]]);
+        match n {
+            0 => r.instr_ptr = word(),
+            1 => r.stack_ptr = word(),
+            2 => r.link = word(),
+            3 => r.local = word(),
+            4..=7 => r.save[n - 4] = value[0],
+            _ => r.flags = value[0],
+        }
+        self.control.set_registers(&r);
+        Some("OK".to_string())
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = parse_range(args)?;
+        let memory = self.control.memory();
+
+        // stop at the first unreadable byte, as GDB allows
+        let bytes: Vec<u8> = (0..len)
+            .map_while(|i| memory.public_read(addr.wrapping_add(i)).ok())
+            .collect();
+        if bytes.is_empty() && len > 0 {
+            return Some("E01".to_string());
+        }
+        Some(hex(&bytes))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = parse_range(range)?;
+        let bytes = unhex(data)?;
+        if bytes.len() != len as usize {
+            return Some("E01".to_string());
+        }
+
+        let memory = self.control.memory_mut();
+        let result = bytes
+            .iter()
+            .zip(0..)
+            .try_for_each(|(&b, i)| memory.public_write(b, addr.wrapping_add(i)));
+        match result {
+            Ok(()) => Some("OK".to_string()),
+            Err(_) => Some("E01".to_string()),
+        }
+    }
+
+    // Z/z type,addr,kind
+    fn breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = u16::from_str_radix(fields.next()?, 16).ok()?;
+        let len = u16::from_str_radix(fields.next()?, 16).ok()?;
+
+        let watches: &[WatchKind] = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return Some("OK".to_string());
+            }
+            "2" => &[WatchKind::Write],
+            "3" => &[WatchKind::Read],
+            "4" => &[WatchKind::Read, WatchKind::Write],
+            _ => return None,
+        };
+
+        let end = addr.checked_add(len.max(1) - 1)?;
+        for &watch in watches {
+            if insert {
+                self.control.watch(addr, end, watch);
+            } else {
+                self.control.unwatch(addr, end, watch);
+            }
+        }
+        Some("OK".to_string())
+    }
+}
+
+fn parse_range(args: &str) -> Option<(u16, u16)> {
+    let (addr, len) = args.split_once(',')?;
+    let addr = u16::from_str_radix(addr, 16).ok()?;
+    let len = u16::from_str_radix(len, 16).ok()?;
+    Some((addr, len))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
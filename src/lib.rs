@@ -0,0 +1,26 @@
+//! STACK85: an 8/16-bit stack machine.
+//!
+//! The machine is made of three parts: the [`ALU`], the byte-addressed
+//! [`Memory`] and the [`Control`] unit that fetches, decodes and executes
+//! instructions. Hosts normally build a `Control` with [`control::new`], load
+//! an image, call [`Control::start`] and then drive it with
+//! [`Control::step`] or [`Control::run`].
+
+pub mod alu;
+pub mod asm;
+pub mod config;
+pub mod console;
+pub mod control;
+pub mod debugger;
+pub mod device;
+pub mod disasm;
+pub mod gdb;
+pub mod machine;
+pub mod memory;
+pub mod savestate;
+pub mod trace;
+
+pub use alu::ALU;
+pub use control::Control;
+pub use device::Device;
+pub use memory::Memory;
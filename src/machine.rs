@@ -0,0 +1,291 @@
+//! Runs a `Control` on a worker thread.
+//!
+//! The worker owns the control unit outright; front-ends talk to it only
+//! through `Command`s sent over a channel, each carrying the channel its
+//! reply goes back on. `Machine` wraps the common commands in blocking
+//! calls.
+
+use crate::control::{self, Control, Fault, Registers, WatchStop};
+use crate::memory::MemFault;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// instructions executed between checks for commands
+const BATCH: usize = 1024;
+
+// how often devices are ticked while the machine waits for an interrupt
+const WAIT_TICK: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Paused,
+    Waiting,
+    Halted,
+    Faulted(Fault),
+    Watchpoint(WatchStop),
+}
+
+/// The state of a machine at one instant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub state: State,
+    pub registers: Registers,
+    pub ram: Vec<u8>,
+}
+
+pub enum Command {
+    /// Stops executing instructions until `Resume`.
+    Pause,
+    /// Undoes `Pause`, and restarts a machine stopped by a watchpoint.
+    Resume,
+    /// Executes one instruction, paused or not, and replies with the
+    /// registers afterwards. Restarts a machine stopped by a watchpoint.
+    Step(Sender<Registers>),
+    State(Sender<State>),
+    Registers(Sender<Registers>),
+    SetRegisters(Registers),
+    /// Reads `len` bytes from an address without device side effects.
+    ReadMemory(u16, u16, Sender<Result<Vec<u8>, MemFault>>),
+    WriteMemory(u16, Vec<u8>, Sender<Result<(), MemFault>>),
+    Snapshot(Sender<Snapshot>),
+    RaiseIrq(u8),
+    /// Replies once the machine is paused, waiting, halted, faulted or
+    /// stopped at a watchpoint.
+    WaitStopped(Sender<State>),
+    Shutdown,
+}
+
+/// A handle to a control unit running on its own thread.
+pub struct Machine {
+    commands: Sender<Command>,
+    worker: Option<JoinHandle<Control>>,
+}
+
+/// Moves `control` onto a worker thread; it runs if it was started.
+pub fn spawn(control: Control) -> Machine {
+    let (commands, receiver) = mpsc::channel();
+    let worker = thread::spawn(move || Worker::new(control, receiver).run());
+
+    Machine {
+        commands,
+        worker: Some(worker),
+    }
+}
+
+impl Machine {
+    /// The raw command channel, for front-ends that manage replies
+    /// themselves.
+    pub fn sender(&self) -> Sender<Command> {
+        self.commands.clone()
+    }
+
+    pub fn send(&self, command: Command) {
+        // the worker only exits on Shutdown, which consumes the machine
+        self.commands
+            .send(command)
+            .expect("machine worker has exited");
+    }
+
+    fn request<T>(&self, command: impl FnOnce(Sender<T>) -> Command) -> T {
+        let (reply, response) = mpsc::channel();
+        self.send(command(reply));
+        response.recv().expect("machine worker has exited")
+    }
+
+    pub fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(Command::Resume);
+    }
+
+    pub fn step(&self) -> Registers {
+        self.request(Command::Step)
+    }
+
+    pub fn state(&self) -> State {
+        self.request(Command::State)
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.request(Command::Registers)
+    }
+
+    pub fn set_registers(&self, registers: Registers) {
+        self.send(Command::SetRegisters(registers));
+    }
+
+    pub fn read_memory(&self, addr: u16, len: u16) -> Result<Vec<u8>, MemFault> {
+        self.request(|reply| Command::ReadMemory(addr, len, reply))
+    }
+
+    pub fn write_memory(&self, addr: u16, data: &[u8]) -> Result<(), MemFault> {
+        self.request(|reply| Command::WriteMemory(addr, data.to_vec(), reply))
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.request(Command::Snapshot)
+    }
+
+    pub fn raise_irq(&self, line: u8) {
+        self.send(Command::RaiseIrq(line));
+    }
+
+    /// Blocks until the machine stops running and returns why.
+    pub fn wait_stopped(&self) -> State {
+        self.request(Command::WaitStopped)
+    }
+
+    /// Stops the worker and returns the control unit.
+    pub fn shutdown(mut self) -> Control {
+        self.send(Command::Shutdown);
+        self.worker
+            .take()
+            .unwrap()
+            .join()
+            .expect("machine worker panicked")
+    }
+}
+
+impl Drop for Machine {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            let _ = self.commands.send(Command::Shutdown);
+            let _ = worker.join();
+        }
+    }
+}
+
+struct Worker {
+    control: Control,
+    commands: Receiver<Command>,
+    paused: bool,
+    waiters: Vec<Sender<State>>,
+}
+
+impl Worker {
+    fn new(control: Control, commands: Receiver<Command>) -> Worker {
+        Worker {
+            control,
+            commands,
+            paused: false,
+            waiters: Vec::new(),
+        }
+    }
+
+    fn state(&self) -> State {
+        if let Some(fault) = self.control.fault() {
+            State::Faulted(fault)
+        } else if let Some(stop) = self.control.watch_stop() {
+            State::Watchpoint(stop)
+        } else if self.control.is_waiting() {
+            State::Waiting
+        } else if !self.control.is_running() {
+            State::Halted
+        } else if self.paused {
+            State::Paused
+        } else {
+            State::Running
+        }
+    }
+
+    fn run(mut self) -> Control {
+        loop {
+            let state = self.state();
+            if state != State::Running {
+                for waiter in self.waiters.drain(..) {
+                    let _ = waiter.send(state);
+                }
+            }
+
+            let command = match state {
+                State::Running => match self.commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                },
+                State::Waiting => match self.commands.recv_timeout(WAIT_TICK) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                _ => match self.commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => break,
+                },
+            };
+
+            match command {
+                Some(Command::Shutdown) => break,
+                Some(command) => self.handle(command),
+                None if state == State::Running => {
+                    for _ in 0..BATCH {
+                        if !self.control.step() {
+                            break;
+                        }
+                    }
+                }
+                None => self.control.tick(),
+            }
+        }
+
+        self.control
+    }
+
+    fn leave_watch_stop(&mut self) {
+        if self.control.watch_stop().is_some() {
+            self.control.start();
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Pause => self.paused = true,
+            Command::Resume => {
+                self.paused = false;
+                self.leave_watch_stop();
+            }
+            Command::Step(reply) => {
+                self.leave_watch_stop();
+                self.control.step();
+                let _ = reply.send(self.control.registers());
+            }
+            Command::State(reply) => {
+                let _ = reply.send(self.state());
+            }
+            Command::Registers(reply) => {
+                let _ = reply.send(self.control.registers());
+            }
+            Command::SetRegisters(registers) => self.control.set_registers(&registers),
+            Command::ReadMemory(addr, len, reply) => {
+                let memory = self.control.memory();
+                let data = (0..len)
+                    .map(|i| memory.public_read(addr.wrapping_add(i)))
+                    .collect();
+                let _ = reply.send(data);
+            }
+            Command::WriteMemory(addr, data, reply) => {
+                let memory = self.control.memory_mut();
+                let result = data
+                    .iter()
+                    .zip(0..)
+                    .try_for_each(|(&b, i)| memory.public_write(b, addr.wrapping_add(i)));
+                let _ = reply.send(result);
+            }
+            Command::Snapshot(reply) => {
+                let _ = reply.send(Snapshot {
+                    state: self.state(),
+                    registers: self.control.registers(),
+                    ram: self.control.memory().ram().to_vec(),
+                });
+            }
+            Command::RaiseIrq(line) if line < control::IRQ_LINES => self.control.raise_irq(line),
+            Command::RaiseIrq(_) => {}
+            Command::WaitStopped(reply) => self.waiters.push(reply),
+            Command::Shutdown => {}
+        }
+    }
+}
@@ -1,6 +1,5 @@
-mod alu;
-mod memory;
-mod control;
+use stack85::control::*;
+use stack85::{alu, control, machine, memory, Device};
 use std::io;
 
 fn main() {
@@ -25,7 +24,7 @@ fn main() {
             test_memory();
             break;
         } else if choice == "3" {
-            control::test_pgm();
+            test_pgm();
             break;
         } else {
             println!("Please enter a valid option.");
@@ -91,7 +90,7 @@ fn test_alu() {
         alu.compute();
 
         println!(
-            "Result: {} ({}), Mult: {}, ONZVC: {:05b}",
+            "Result: {} ({}), Mult: {}, HONZVC: {:06b}",
             alu.result(),
             alu.result() as i8,
             alu.result() as u16 | (alu.res_hi() as u16) << 8,
@@ -132,7 +131,7 @@ fn test_memory() {
             }
         };
 
-        if address >= memory::MEM_SIZE {
+        if address as usize >= memory::MEM_SIZE {
             println!(
                 "Address: Please enter a number from 0 to {}.",
                 memory::MEM_SIZE - 1
@@ -145,7 +144,9 @@ fn test_memory() {
         if choice.trim().eq_ignore_ascii_case("w") {
             println!("Enter value:");
             let mut data = String::new();
-            io::stdin().read_line(&mut data).expect("Failed to read line");
+            io::stdin()
+                .read_line(&mut data)
+                .expect("Failed to read line");
             let data: u8 = match data.trim().parse() {
                 Ok(num) => num,
                 Err(_) => {
@@ -154,10 +155,123 @@ fn test_memory() {
                 }
             };
 
-            memory.write(data);
-            println!("Wrote {} to address {}.", data, address);
+            match memory.write(data) {
+                Ok(()) => println!("Wrote {} to address {}.", data, address),
+                Err(fault) => println!("{}", fault),
+            }
+        } else {
+            match memory.read() {
+                Ok(data) => println!("Read {}.", data),
+                Err(fault) => println!("{}", fault),
+            }
+        }
+    }
+}
+
+// 175 is a status byte: writing 1 prints the printable characters of the
+// text buffer at 176..255
+struct TextBuffer {
+    status: u8,
+    text: [u8; 79],
+}
+
+impl TextBuffer {
+    fn new() -> TextBuffer {
+        TextBuffer {
+            status: 0,
+            text: [0; 79],
+        }
+    }
+}
+
+impl Device for TextBuffer {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        if offset > 0 {
+            self.text[offset as usize - 1] = value;
+            return;
+        }
+
+        self.status = value;
+        if value == 1 {
+            let line: String = self
+                .text
+                .iter()
+                .filter(|ch| (32..=126).contains(*ch))
+                .map(|&ch| ch as char)
+                .collect();
+            println!("{}", line);
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        if offset == 0 {
+            self.status
         } else {
-            println!("Read {}.", memory.read());
+            self.text[offset as usize - 1]
         }
     }
 }
+
+fn test_pgm() {
+    let program: Vec<u8> = vec![
+        // initialize
+        SET_STACK,
+        0x00,
+        0x01,
+        CONST_3,
+        SAVE_0,
+        // START of program
+        ENTER,
+        IMM_CONST,
+        48,
+        IMM_CONST,
+        16,
+        IMM_CONST_D,
+        0x10,
+        0x00, // SUM_EQUALS_64
+        CALL,
+        LEAVE,
+        WAIT,
+        // subroutine SUM_EQUALS_64
+        LOCAL_1,
+        LOCAL_0,
+        ADD,
+        LOCAL_2,
+        IMM_CONST,
+        64,
+        COMPARE,
+        IF_EQUAL,
+        IMM_BRANCH,
+        2,
+        IMM_BRANCH,
+        3,
+        CONST_1,
+        SAVE_0,
+        GOBACK,
+        CONST_0,
+        SAVE_0,
+        GOBACK,
+    ];
+
+    control::debug_print(&program);
+    println!();
+
+    let mut control = control::new();
+    control
+        .load_image(0, &program)
+        .expect("program fits in memory");
+    control
+        .memory_mut()
+        .map_device(175, 80, Box::new(TextBuffer::new()))
+        .expect("text buffer fits in memory");
+
+    control.start();
+
+    let machine = machine::spawn(control);
+    machine.wait_stopped();
+    machine.shutdown().view();
+}
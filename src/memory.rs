@@ -1,45 +1,647 @@
+use crate::device::Device;
+use std::fmt;
 
-pub const MEM_SIZE: u16 = 8192;
+pub const MEM_SIZE: usize = 8192;
 
+/// Every address the 16-bit IP and SP can reach.
+pub const ADDRESS_SPACE: usize = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+    /// Nothing is mapped at the address.
+    Unmapped,
+    /// A write to ROM while ROM writes fault.
+    ReadOnly,
+    /// A write to the bank register selecting a bank that does not exist.
+    NoBank,
+}
+
+/// An access to an address outside of memory, or a write to ROM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemFault {
+    pub addr: u16,
+    pub access: Access,
+    pub cause: Cause,
+}
+
+impl fmt::Display for MemFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = match self.access {
+            Access::Fetch => "fetch",
+            Access::Read => "read",
+            Access::Write => "write",
+        };
+        match self.cause {
+            Cause::Unmapped => write!(f, "{} fault at {:04X}", access, self.addr),
+            Cause::ReadOnly => write!(f, "write to ROM at {:04X}", self.addr),
+            Cause::NoBank => write!(f, "no such bank selected at {:04X}", self.addr),
+        }
+    }
+}
+
+impl std::error::Error for MemFault {}
+
+/// An image that does not fit in memory, or in a bank's window, at the
+/// address it is loaded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadError {
+    pub base: u16,
+    pub len: usize,
+    pub mem_size: usize,
+    /// The bank the image was loaded into, if any.
+    pub bank: Option<u8>,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(
+                f,
+                "{} byte image at {:04X} does not fit in bank {}",
+                self.len, self.base, bank
+            ),
+            None => write!(
+                f,
+                "{} byte image at {:04X} does not fit in {} bytes of memory",
+                self.len, self.base, self.mem_size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// A device range that cannot be mapped, and where it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    Empty(u16),
+    WrapsAround(u16),
+    Overlaps(u16),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Empty(start) => write!(f, "empty device range at {:04X}", start),
+            MapError::WrapsAround(start) => write!(
+                f,
+                "device range at {:04X} wraps around the address space",
+                start
+            ),
+            MapError::Overlaps(start) => {
+                write!(f, "device range at {:04X} overlaps another device", start)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// A write that changes the stored value.
+    Change,
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Change => "change",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+/// Watches the addresses `start..=end` for one kind of access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+/// An access that triggered a watchpoint. For reads `old` and `new` are
+/// both the value read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub kind: WatchKind,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            WatchKind::Read => write!(f, "read {:02X} from {:04X}", self.new, self.addr),
+            _ => write!(
+                f,
+                "{} {:04X}: {:02X} -> {:02X}",
+                self.kind, self.addr, self.old, self.new
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Ram,
+    Rom,
+}
+
+/// What happens when the program writes to ROM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomWrites {
+    Fault,
+    Ignore,
+}
+
+/// The addresses `start..=end` hold RAM or ROM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u16,
+    pub end: u16,
+    pub kind: RegionKind,
+}
+
+/// Bank switching: `count` banks of RAM take turns at the window
+/// `start..=end`, selected by writing a bank number to the bank register
+/// at `register`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Banking {
+    pub start: u16,
+    pub end: u16,
+    pub register: u16,
+    /// At most 256, so that any bank can be selected with one byte.
+    pub count: usize,
+}
+
+/// A write made through the MAR, as recorded by the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemWrite {
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+struct Mapping {
+    start: u16,
+    end: u16, // inclusive
+    device: Box<dyn Device>,
+}
+
+/// The memory bus: byte-addressed RAM with a memory address register (MAR)
+/// and devices mapped over ranges of the address space.
+///
+/// The control unit accesses memory by setting the MAR and then reading or
+/// writing; hosts can use `public_read`/`public_write` instead, which do not
+/// trigger device read side effects. Accesses outside of RAM and of every
+/// device return a `MemFault`.
+///
+/// Until a region is mapped all of memory is RAM. Once any is, addresses
+/// outside every region and device are holes that fault, and writes
+/// through the MAR to ROM fault or are ignored as `set_rom_writes` says;
+/// hosts may still write to ROM.
+///
+/// With banking mapped, the window holds the selected bank and the others
+/// are kept aside until selected; the bank register reads back the
+/// selected bank and, like a device, takes precedence over RAM and ROM.
+///
+/// Watchpoints only see accesses through the MAR, instruction fetches
+/// included; the first hit is kept until taken with `take_watch_hit`. The
+/// journal, when enabled, likewise records only writes through the MAR.
 pub struct Memory {
     mem: Vec<u8>,
     mar: u16,
+    devices: Vec<Mapping>,
+    regions: Vec<Region>,
+    rom_writes: RomWrites,
+    banking: Option<Banking>,
+    bank: u8,
+    // every bank's contents but the selected one's, which is in the window
+    banks: Vec<Vec<u8>>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
+    journal: Option<Vec<MemWrite>>,
 }
 
-pub fn new(size: u16) -> Memory {
+/// Creates a zero-filled memory of `size` bytes, at most `ADDRESS_SPACE`.
+pub fn new(size: usize) -> Memory {
+    assert!(
+        size <= ADDRESS_SPACE,
+        "memory larger than the address space"
+    );
     Memory {
-        mem: vec![0; size as usize],
+        mem: vec![0; size],
         mar: 0,
+        devices: Vec::new(),
+        regions: Vec::new(),
+        rom_writes: RomWrites::Fault,
+        banking: None,
+        bank: 0,
+        banks: Vec::new(),
+        watchpoints: Vec::new(),
+        watch_hit: None,
+        journal: None,
     }
 }
 
-#[allow(dead_code)]
 impl Memory {
     pub fn set_addr(&mut self, addr: u16) {
         self.mar = addr;
     }
 
-    pub fn read(&self) -> u8 {
-        assert!(self.mar < self.mem.len() as u16);
-        self.mem[self.mar as usize]
+    pub fn addr(&self) -> u16 {
+        self.mar
+    }
+
+    /// Maps `device` over `size` bytes starting at `start`; device ranges
+    /// take precedence over RAM.
+    ///
+    /// Fails if the range is empty, wraps around the address space or
+    /// overlaps another device.
+    pub fn map_device(
+        &mut self,
+        start: u16,
+        size: u16,
+        device: Box<dyn Device>,
+    ) -> Result<(), MapError> {
+        if size == 0 {
+            return Err(MapError::Empty(start));
+        }
+        let end = start
+            .checked_add(size - 1)
+            .ok_or(MapError::WrapsAround(start))?;
+        if self
+            .devices
+            .iter()
+            .any(|m| end >= m.start && start <= m.end)
+        {
+            return Err(MapError::Overlaps(start));
+        }
+
+        self.devices.push(Mapping { start, end, device });
+        Ok(())
+    }
+
+    /// Declares `start..=end` as RAM or ROM; see `Memory`.
+    ///
+    /// Panics if the range is backwards, reaches past the end of memory or
+    /// overlaps another region.
+    pub fn map_region(&mut self, start: u16, end: u16, kind: RegionKind) {
+        assert!(start <= end);
+        assert!((end as usize) < self.mem.len(), "region past end of memory");
+        assert!(
+            self.regions.iter().all(|r| end < r.start || start > r.end),
+            "region overlaps another region"
+        );
+
+        self.regions.push(Region { start, end, kind });
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn set_rom_writes(&mut self, rom_writes: RomWrites) {
+        self.rom_writes = rom_writes;
+    }
+
+    pub fn rom_writes(&self) -> RomWrites {
+        self.rom_writes
+    }
+
+    /// Maps bank switching as `banking` describes, with bank 0 selected and
+    /// every bank zeroed.
+    ///
+    /// Panics if banking is already mapped, if there are no banks or more
+    /// than 256, if the window is backwards or reaches past the end of
+    /// memory, or if the register is inside the window.
+    pub fn map_banks(&mut self, banking: Banking) {
+        assert!(self.banking.is_none(), "banking already mapped");
+        assert!(banking.count > 0 && banking.count <= 256);
+        assert!(banking.start <= banking.end);
+        assert!(
+            (banking.end as usize) < self.mem.len(),
+            "bank window past end of memory"
+        );
+        assert!(
+            banking.register < banking.start || banking.register > banking.end,
+            "bank register inside the window"
+        );
+
+        let window = banking.start as usize..=banking.end as usize;
+        self.mem[window.clone()].fill(0);
+        self.banks = vec![vec![0; window.count()]; banking.count];
+        self.bank = 0;
+        self.banking = Some(banking);
+    }
+
+    pub fn banking(&self) -> Option<Banking> {
+        self.banking
+    }
+
+    /// The selected bank.
+    pub fn bank(&self) -> u8 {
+        self.bank
+    }
+
+    /// Shows `bank` in the window, returning false if there is no such
+    /// bank.
+    pub fn select_bank(&mut self, bank: u8) -> bool {
+        let banking = match self.banking {
+            Some(banking) if (bank as usize) < banking.count => banking,
+            _ => return false,
+        };
+
+        let window = banking.start as usize..=banking.end as usize;
+        let selected = self.bank as usize;
+        self.banks[selected].copy_from_slice(&self.mem[window.clone()]);
+        self.mem[window].copy_from_slice(&self.banks[bank as usize]);
+        self.bank = bank;
+        true
+    }
+
+    /// The contents of `bank`, or None if there is no such bank.
+    pub fn bank_contents(&self, bank: u8) -> Option<&[u8]> {
+        let banking = self.banking.filter(|b| (bank as usize) < b.count)?;
+        if bank == self.bank {
+            Some(&self.mem[banking.start as usize..=banking.end as usize])
+        } else {
+            Some(&self.banks[bank as usize])
+        }
+    }
+
+    /// Copies `image` into `bank` as if loaded at `base` while the bank is
+    /// selected.
+    pub fn load_bank(&mut self, bank: u8, base: u16, image: &[u8]) -> Result<(), LoadError> {
+        let error = LoadError {
+            base,
+            len: image.len(),
+            mem_size: self.mem.len(),
+            bank: Some(bank),
+        };
+        let banking = match self.banking {
+            Some(banking) if (bank as usize) < banking.count && base >= banking.start => banking,
+            _ => return Err(error),
+        };
+        if base as usize + image.len() > banking.end as usize + 1 {
+            return Err(error);
+        }
+
+        let (contents, start) = if bank == self.bank {
+            (&mut self.mem, base as usize)
+        } else {
+            (
+                &mut self.banks[bank as usize],
+                (base - banking.start) as usize,
+            )
+        };
+        contents[start..start + image.len()].copy_from_slice(image);
+        Ok(())
+    }
+
+    fn is_bank_register(&self, addr: u16) -> bool {
+        self.banking.is_some_and(|b| b.register == addr)
+    }
+
+    // what backs `addr` when no device is mapped there
+    fn region(&self, addr: u16) -> Option<RegionKind> {
+        if addr as usize >= self.mem.len() {
+            None
+        } else if self.regions.is_empty() {
+            Some(RegionKind::Ram)
+        } else {
+            self.regions
+                .iter()
+                .find(|r| r.start <= addr && addr <= r.end)
+                .map(|r| r.kind)
+        }
+    }
+
+    fn mapping(&mut self, addr: u16) -> Option<&mut Mapping> {
+        self.devices
+            .iter_mut()
+            .find(|m| m.start <= addr && addr <= m.end)
+    }
+
+    pub fn read(&mut self) -> Result<u8, MemFault> {
+        let addr = self.mar;
+        let value = match self.mapping(addr) {
+            Some(m) => m.device.read(addr - m.start),
+            None => self.public_read(addr)?,
+        };
+
+        self.check_watch(addr, WatchKind::Read, value, value);
+        Ok(value)
+    }
+
+    pub fn write(&mut self, value: u8) -> Result<(), MemFault> {
+        let addr = self.mar;
+        if self.region(addr) == Some(RegionKind::Rom)
+            && self.mapping(addr).is_none()
+            && !self.is_bank_register(addr)
+        {
+            return match self.rom_writes {
+                RomWrites::Fault => Err(MemFault {
+                    addr,
+                    access: Access::Write,
+                    cause: Cause::ReadOnly,
+                }),
+                RomWrites::Ignore => Ok(()),
+            };
+        }
+
+        if self.watchpoints.is_empty() && self.journal.is_none() {
+            return self.public_write(value, addr);
+        }
+
+        let old = self.public_read(addr);
+        self.public_write(value, addr)?;
+
+        let old = old.unwrap_or(value);
+        if let Some(journal) = &mut self.journal {
+            journal.push(MemWrite {
+                addr,
+                old,
+                new: value,
+            });
+        }
+        self.check_watch(addr, WatchKind::Write, old, value);
+        if old != value {
+            self.check_watch(addr, WatchKind::Change, old, value);
+        }
+        Ok(())
+    }
+
+    fn check_watch(&mut self, addr: u16, kind: WatchKind, old: u8, new: u8) {
+        if self.watch_hit.is_none()
+            && self
+                .watchpoints
+                .iter()
+                .any(|w| w.kind == kind && w.start <= addr && addr <= w.end)
+        {
+            self.watch_hit = Some(WatchHit {
+                addr,
+                kind,
+                old,
+                new,
+            });
+        }
+    }
+
+    /// Watches `start..=end` for `kind` accesses. Adding a watchpoint twice
+    /// has no effect.
+    pub fn watch(&mut self, start: u16, end: u16, kind: WatchKind) {
+        assert!(start <= end);
+        let watchpoint = Watchpoint { start, end, kind };
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Removes a watchpoint added with the same arguments, returning
+    /// whether there was one.
+    pub fn unwatch(&mut self, start: u16, end: u16, kind: WatchKind) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints
+            .retain(|w| *w != Watchpoint { start, end, kind });
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Returns and clears the first watchpoint hit since the last call.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
     }
 
-    pub fn write(&mut self, value: u8) {
-        assert!(self.mar < self.mem.len() as u16);
-        self.mem[self.mar as usize] = value;
+    /// Starts or stops recording writes; stopping discards the record.
+    pub fn set_journal(&mut self, enabled: bool) {
+        self.journal = if enabled { Some(Vec::new()) } else { None };
     }
 
-    pub fn public_read(&self, addr: u16) -> u8 {
-        assert!(addr < self.mem.len() as u16);
-        self.mem[addr as usize]
+    /// Returns and clears the writes recorded since the last call.
+    pub fn take_journal(&mut self) -> Vec<MemWrite> {
+        match &mut self.journal {
+            Some(journal) => std::mem::take(journal),
+            None => Vec::new(),
+        }
     }
 
-    pub fn public_write(&mut self, value: u8, addr: u16) {
-        assert!(addr < self.mem.len() as u16);
-        self.mem[addr as usize] = value;
+    /// Puts back the value a journaled write replaced, reselecting the
+    /// previous bank for a write to the bank register. Writes to devices
+    /// cannot be undone and are ignored.
+    pub fn undo(&mut self, write: &MemWrite) {
+        if self.mapping(write.addr).is_some() {
+            return;
+        }
+        if self.is_bank_register(write.addr) {
+            self.select_bank(write.old);
+        } else if let Some(cell) = self.mem.get_mut(write.addr as usize) {
+            *cell = write.old;
+        }
     }
 
-    pub fn load_image(&mut self, image: Vec<u8>) {
-        self.mem = image;
+    pub fn public_read(&self, addr: u16) -> Result<u8, MemFault> {
+        if let Some(m) = self
+            .devices
+            .iter()
+            .find(|m| m.start <= addr && addr <= m.end)
+        {
+            return Ok(m.device.peek(addr - m.start));
+        }
+        if self.is_bank_register(addr) {
+            return Ok(self.bank);
+        }
+
+        match self.region(addr) {
+            Some(_) => Ok(self.mem[addr as usize]),
+            None => Err(MemFault {
+                addr,
+                access: Access::Read,
+                cause: Cause::Unmapped,
+            }),
+        }
+    }
+
+    pub fn public_write(&mut self, value: u8, addr: u16) -> Result<(), MemFault> {
+        if let Some(m) = self.mapping(addr) {
+            m.device.write(addr - m.start, value);
+            return Ok(());
+        }
+        if self.is_bank_register(addr) {
+            if !self.select_bank(value) {
+                return Err(MemFault {
+                    addr,
+                    access: Access::Write,
+                    cause: Cause::NoBank,
+                });
+            }
+            return Ok(());
+        }
+
+        match self.region(addr) {
+            Some(_) => {
+                self.mem[addr as usize] = value;
+                Ok(())
+            }
+            None => Err(MemFault {
+                addr,
+                access: Access::Write,
+                cause: Cause::Unmapped,
+            }),
+        }
+    }
+
+    /// Copies `image` into RAM starting at `base`; the memory keeps its
+    /// size. The part of the image in the bank window, if any, goes to the
+    /// selected bank.
+    pub fn load_image(&mut self, base: u16, image: &[u8]) -> Result<(), LoadError> {
+        let start = base as usize;
+        let error = LoadError {
+            base,
+            len: image.len(),
+            mem_size: self.mem.len(),
+            bank: None,
+        };
+
+        match self.mem.get_mut(start..start + image.len()) {
+            Some(ram) => {
+                ram.copy_from_slice(image);
+                Ok(())
+            }
+            None => Err(error),
+        }
     }
-}
\ No newline at end of file
+
+    pub fn size(&self) -> usize {
+        self.mem.len()
+    }
+
+    /// The contents of RAM, without any devices; the bank window holds the
+    /// selected bank.
+    pub fn ram(&self) -> &[u8] {
+        &self.mem
+    }
+
+    /// Ticks every device, returning the interrupt lines they raise.
+    pub fn tick(&mut self) -> u8 {
+        self.devices
+            .iter_mut()
+            .fold(0, |lines, m| lines | m.device.tick())
+    }
+}
@@ -0,0 +1,134 @@
+//! Save states: the full state of a control unit in a file.
+//!
+//! A save state starts with the magic `S85S` and a version byte, followed
+//! by:
+//!
+//! - IP, SP, link and local, 2 bytes each
+//! - save_0 to save_3
+//! - running and waiting, one byte each (0 or 1)
+//! - the vector base (2 bytes), interrupt mask and pending interrupts
+//! - the stack base (2 bytes), whether there is a stack limit (0 or 1) and
+//!   the limit (2 bytes, 0 without one)
+//! - the call mode: 0 link, 1 stack
+//! - the fault: 0 for none; 1 for a memory fault, followed by the faulting
+//!   IP (2 bytes), the address (2 bytes), the access (0 fetch, 1 read, 2
+//!   write) and the cause (0 unmapped, 1 read-only, 2 no such bank); or 2
+//!   for a stack overflow and 3 for an underflow, followed by the faulting
+//!   IP and SP (2 bytes each)
+//! - the ALU's X, Y, op, result, res_hi, flags, and high bytes of X and Y
+//! - the MAR (2 bytes), then the RAM size (4 bytes) and contents
+//! - the selected bank, the number of banks (2 bytes, 0 without banking)
+//!   and each bank's size (4 bytes) and contents
+//!
+//! Every multi-byte field is stored low byte first. Devices, watchpoints
+//! and tracers are not part of the state; a loaded state keeps those of the
+//! control unit it is loaded into, whose memory must be the same size and
+//! have the same banks.
+
+use crate::control::Control;
+use crate::memory;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+pub const MAGIC: &[u8; 4] = b"S85S";
+pub const VERSION: u8 = 1;
+
+/// Writes the state of `control` to the file at `path`.
+pub fn save(control: &Control, path: impl AsRef<Path>) -> io::Result<()> {
+    let mut output = BufWriter::new(File::create(path)?);
+    control.save_state(&mut output)?;
+    output.flush()
+}
+
+/// Replaces the state of `control` with the one in the file at `path`.
+pub fn load(control: &mut Control, path: impl AsRef<Path>) -> io::Result<()> {
+    control.load_state(&mut BufReader::new(File::open(path)?))
+}
+
+pub(crate) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) struct Encoder<'a> {
+    output: &'a mut dyn Write,
+}
+
+pub(crate) fn encoder(output: &mut dyn Write) -> io::Result<Encoder<'_>> {
+    output.write_all(MAGIC)?;
+    output.write_all(&[VERSION])?;
+    Ok(Encoder { output })
+}
+
+impl Encoder<'_> {
+    pub fn byte(&mut self, value: u8) -> io::Result<()> {
+        self.output.write_all(&[value])
+    }
+
+    pub fn word(&mut self, value: u16) -> io::Result<()> {
+        self.output.write_all(&value.to_le_bytes())
+    }
+
+    pub fn bool(&mut self, value: bool) -> io::Result<()> {
+        self.byte(value as u8)
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> io::Result<()> {
+        self.output.write_all(&(value.len() as u32).to_le_bytes())?;
+        self.output.write_all(value)
+    }
+}
+
+pub(crate) struct Decoder<'a> {
+    input: &'a mut dyn Read,
+}
+
+pub(crate) fn decoder(input: &mut dyn Read) -> io::Result<Decoder<'_>> {
+    let mut header = [0; 5];
+    input.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(invalid("not a stack85 save state"));
+    }
+    if header[4] != VERSION {
+        return Err(invalid(&format!(
+            "unsupported save state version {}",
+            header[4]
+        )));
+    }
+    Ok(Decoder { input })
+}
+
+impl Decoder<'_> {
+    pub fn byte(&mut self) -> io::Result<u8> {
+        let mut buf = [0];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn word(&mut self) -> io::Result<u16> {
+        let mut buf = [0; 2];
+        self.input.read_exact(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("bad flag in save state")),
+        }
+    }
+
+    pub fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let mut len = [0; 4];
+        self.input.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > memory::ADDRESS_SPACE {
+            return Err(invalid("memory larger than the address space"));
+        }
+
+        let mut value = vec![0; len];
+        self.input.read_exact(&mut value)?;
+        Ok(value)
+    }
+}
@@ -0,0 +1,314 @@
+//! Execution traces: a record of every instruction a control unit executes.
+//!
+//! A trace file starts with the magic `S85T` and a version byte, followed
+//! by one record per step:
+//!
+//! - a tag byte: bit 0 set if an interrupt was taken instead of an
+//!   instruction, bit 1 set if SP changed by more than a signed byte
+//! - IP before the step, 2 bytes
+//! - the number of instruction bytes, then the bytes themselves
+//! - SP before the step, 2 bytes
+//! - SP after the step: a signed byte delta, or 2 bytes if tag bit 1 is set
+//! - the ALU flags after the step
+//! - the number of memory writes, then each write's address (2 bytes) and
+//!   value
+//!
+//! Every 16-bit field is stored low byte first.
+
+use crate::asm::Assembly;
+use crate::disasm;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+pub const MAGIC: &[u8; 4] = b"S85T";
+pub const VERSION: u8 = 1;
+
+const TAG_INTERRUPT: u8 = 0b01;
+const TAG_WIDE_SP: u8 = 0b10;
+
+/// One executed instruction, or one interrupt taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub ip: u16,
+    pub interrupt: bool,
+    /// The opcode and operand bytes; empty for an interrupt.
+    pub bytes: Vec<u8>,
+    pub sp_before: u16,
+    pub sp_after: u16,
+    pub flags: u8,
+    /// The writes the step made through the memory address register, as
+    /// (address, value) pairs in order.
+    pub writes: Vec<(u16, u8)>,
+}
+
+/// Receives a `Step` after every instruction a control unit executes.
+pub trait Tracer: Send {
+    fn step(&mut self, step: &Step);
+
+    /// Called when the tracer is removed from the control unit; reports
+    /// any error the tracer could not report from `step`.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Step {
+    /// Formats the step as one line: the instruction listing, SP, flags and
+    /// any memory writes.
+    pub fn text(&self, assembly: &Assembly) -> String {
+        let mut text = if self.interrupt {
+            format!("{:04X}  interrupt", self.ip)
+        } else {
+            let ip = self.ip;
+            let bytes = &self.bytes;
+            let mut instruction =
+                disasm::decode_with(|a| bytes.get(a.wrapping_sub(ip) as usize).copied(), ip);
+            // the trace does not hold the instruction a condition skips
+            instruction.skip = None;
+            instruction.listing(assembly)
+        };
+
+        let _ = write!(
+            text,
+            "{:w$}SP {:04X}->{:04X} FL {:06b}",
+            "",
+            self.sp_before,
+            self.sp_after,
+            self.flags,
+            w = 48usize.saturating_sub(text.len()).max(1)
+        );
+        for (addr, value) in &self.writes {
+            let _ = write!(text, " [{:04X}]={:02X}", addr, value);
+        }
+        text
+    }
+}
+
+/// Writes steps to a trace file as they happen.
+pub struct Writer<W: Write> {
+    output: W,
+    error: Option<io::Error>,
+}
+
+/// Starts a trace on `output`, writing the header.
+pub fn writer<W: Write>(mut output: W) -> io::Result<Writer<W>> {
+    output.write_all(MAGIC)?;
+    output.write_all(&[VERSION])?;
+    Ok(Writer {
+        output,
+        error: None,
+    })
+}
+
+/// Creates a trace file at `path`.
+pub fn create(path: impl AsRef<Path>) -> io::Result<Writer<BufWriter<File>>> {
+    writer(BufWriter::new(File::create(path)?))
+}
+
+impl<W: Write> Writer<W> {
+    pub fn write_step(&mut self, step: &Step) -> io::Result<()> {
+        let delta = step.sp_after.wrapping_sub(step.sp_before) as i16;
+        let wide = delta != delta as i8 as i16;
+
+        let mut tag = 0;
+        if step.interrupt {
+            tag |= TAG_INTERRUPT;
+        }
+        if wide {
+            tag |= TAG_WIDE_SP;
+        }
+
+        let mut record = vec![tag];
+        record.extend_from_slice(&step.ip.to_le_bytes());
+        record.push(step.bytes.len() as u8);
+        record.extend_from_slice(&step.bytes);
+        record.extend_from_slice(&step.sp_before.to_le_bytes());
+        if wide {
+            record.extend_from_slice(&step.sp_after.to_le_bytes());
+        } else {
+            record.push(delta as u8);
+        }
+        record.push(step.flags);
+        record.push(step.writes.len() as u8);
+        for (addr, value) in &step.writes {
+            record.extend_from_slice(&addr.to_le_bytes());
+            record.push(*value);
+        }
+
+        self.output.write_all(&record)
+    }
+}
+
+impl<W: Write + Send> Tracer for Writer<W> {
+    fn step(&mut self, step: &Step) {
+        if self.error.is_none() {
+            if let Err(e) = self.write_step(step) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.output.flush(),
+        }
+    }
+}
+
+/// Reads the steps of a trace file one at a time.
+pub struct Reader<R: Read> {
+    input: R,
+}
+
+/// Opens a trace on `input`, checking the header.
+pub fn reader<R: Read>(mut input: R) -> io::Result<Reader<R>> {
+    let mut header = [0; 5];
+    input.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(invalid("not a stack85 trace"));
+    }
+    if header[4] != VERSION {
+        return Err(invalid(&format!("unsupported trace version {}", header[4])));
+    }
+    Ok(Reader { input })
+}
+
+/// Opens the trace file at `path`.
+pub fn open(path: impl AsRef<Path>) -> io::Result<Reader<BufReader<File>>> {
+    reader(BufReader::new(File::open(path)?))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<R: Read> Reader<R> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut buf = [0];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn word(&mut self) -> io::Result<u16> {
+        let mut buf = [0; 2];
+        self.input.read_exact(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    /// The next step, or None at the end of the trace.
+    pub fn next_step(&mut self) -> io::Result<Option<Step>> {
+        let tag = match self.byte() {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let ip = self.word()?;
+        let len = self.byte()? as usize;
+        if len > 4 {
+            return Err(invalid("instruction longer than 4 bytes"));
+        }
+        let mut bytes = vec![0; len];
+        self.input.read_exact(&mut bytes)?;
+
+        let sp_before = self.word()?;
+        let sp_after = if tag & TAG_WIDE_SP != 0 {
+            self.word()?
+        } else {
+            sp_before.wrapping_add(self.byte()? as i8 as u16)
+        };
+        let flags = self.byte()?;
+
+        let count = self.byte()?;
+        let mut writes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            writes.push((self.word()?, self.byte()?));
+        }
+
+        Ok(Some(Step {
+            ip,
+            interrupt: tag & TAG_INTERRUPT != 0,
+            bytes,
+            sp_before,
+            sp_after,
+            flags,
+            writes,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Step>;
+
+    fn next(&mut self) -> Option<io::Result<Step>> {
+        self.next_step().transpose()
+    }
+}
+
+/// Where two traces first differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The number of steps the traces have in common.
+    pub index: u64,
+    /// The differing steps; None where that trace has already ended.
+    pub left: Option<Step>,
+    pub right: Option<Step>,
+}
+
+/// Compares two traces step by step, returning the first divergence, or
+/// None if they are identical.
+pub fn diff(
+    left: impl Iterator<Item = io::Result<Step>>,
+    right: impl Iterator<Item = io::Result<Step>>,
+) -> io::Result<Option<Divergence>> {
+    let mut left = left;
+    let mut right = right;
+    let mut index = 0;
+
+    loop {
+        let l = left.next().transpose()?;
+        let r = right.next().transpose()?;
+        if l.is_none() && r.is_none() {
+            return Ok(None);
+        }
+        if l != r {
+            return Ok(Some(Divergence {
+                index,
+                left: l,
+                right: r,
+            }));
+        }
+        index += 1;
+    }
+}
+
+impl Divergence {
+    /// Names the fields that differ between the two steps.
+    pub fn fields(&self) -> Vec<&'static str> {
+        let (l, r) = match (&self.left, &self.right) {
+            (Some(l), Some(r)) => (l, r),
+            _ => return vec!["length"],
+        };
+
+        let mut fields = Vec::new();
+        if l.ip != r.ip || l.interrupt != r.interrupt {
+            fields.push("ip");
+        }
+        if l.bytes != r.bytes {
+            fields.push("instruction");
+        }
+        if l.sp_before != r.sp_before || l.sp_after != r.sp_after {
+            fields.push("sp");
+        }
+        if l.flags != r.flags {
+            fields.push("flags");
+        }
+        if l.writes != r.writes {
+            fields.push("writes");
+        }
+        fields
+    }
+}
@@ -0,0 +1,447 @@
+use stack85::alu::*;
+use stack85::control::*;
+use stack85::control::{self, Control};
+
+const STACK: u16 = 0x4000;
+
+// Runs the pieces of `code` with an empty stack at STACK, followed by WAIT.
+fn run(code: &[&[u8]]) -> Control {
+    let mut program = vec![SET_STACK, STACK as u8, (STACK >> 8) as u8];
+    program.extend_from_slice(&code.concat());
+    program.push(WAIT);
+
+    let mut control = control::new();
+    control.load_image(0, &program).unwrap();
+    control.start();
+    control.run();
+
+    assert_eq!(control.fault(), None);
+    control
+}
+
+// The bytes on the stack, bottom first.
+fn stack(control: &Control) -> Vec<u8> {
+    (STACK + 1..=control.stack_ptr())
+        .map(|addr| control.memory().public_read(addr).unwrap())
+        .collect()
+}
+
+fn flag(control: &Control, flag: usize) -> bool {
+    control.flags() & 1 << flag != 0
+}
+
+fn byte(value: u8) -> [u8; 2] {
+    [IMM_CONST, value]
+}
+
+// pushes a word, low byte first
+fn word(value: u16) -> [u8; 3] {
+    [IMM_CONST_D, value as u8, (value >> 8) as u8]
+}
+
+#[test]
+fn add_carry_chain() {
+    // 0x0001FFFF + 0x00000001, low words first
+    let control = run(&[
+        &word(0xFFFF),
+        &word(0x0001),
+        &[ADD_W, 0],
+        &word(0x0001),
+        &word(0x0000),
+        &[ADD_CARRY_W, 0],
+    ]);
+    assert_eq!(stack(&control), vec![0x00, 0x00, 0x02, 0x00]);
+    assert!(!flag(&control, FLAG_C));
+}
+
+#[test]
+fn sub_borrow_chain() {
+    // 0x00020000 - 0x00000001, low words first
+    let control = run(&[
+        &word(0x0000),
+        &word(0x0001),
+        &[SUBTRACT_W, 0],
+        &word(0x0002),
+        &word(0x0000),
+        &[SUB_BORROW_W, 0],
+    ]);
+    assert_eq!(stack(&control), vec![0xFF, 0xFF, 0x01, 0x00]);
+    assert!(!flag(&control, FLAG_C));
+
+    // a borrow out of the top word
+    let control = run(&[
+        &word(0x0000),
+        &word(0x0001),
+        &[SUBTRACT_W, 0],
+        &word(0x0000),
+        &word(0x0000),
+        &[SUB_BORROW_W, 0],
+    ]);
+    assert_eq!(stack(&control), vec![0xFF, 0xFF, 0xFF, 0xFF]);
+    assert!(flag(&control, FLAG_C));
+    assert!(flag(&control, FLAG_N));
+}
+
+#[test]
+fn signed_overflow() {
+    let control = run(&[&word(0x7FFF), &word(0x0001), &[ADD_W, 0]]);
+    assert_eq!(stack(&control), vec![0x00, 0x80]);
+    assert!(flag(&control, FLAG_N));
+    assert!(flag(&control, FLAG_V));
+    assert!(!flag(&control, FLAG_C));
+
+    let control = run(&[&word(0x7FFF), &[INC_W, 1]]);
+    assert_eq!(stack(&control), vec![0x00, 0x80]);
+    assert!(flag(&control, FLAG_N));
+    assert!(flag(&control, FLAG_V));
+
+    let control = run(&[&word(0x8000), &word(0x0001), &[SUBTRACT_W, 0]]);
+    assert_eq!(stack(&control), vec![0xFF, 0x7F]);
+    assert!(!flag(&control, FLAG_N));
+    assert!(flag(&control, FLAG_V));
+    assert!(!flag(&control, FLAG_C));
+
+    let control = run(&[&word(0x8000), &[DEC_W, 1]]);
+    assert_eq!(stack(&control), vec![0xFF, 0x7F]);
+    assert!(flag(&control, FLAG_V));
+
+    let control = run(&[&word(0x7FFF), &word(0x8000), &[COMPARE_W, 0]]);
+    assert_eq!(stack(&control), vec![]);
+    assert!(flag(&control, FLAG_N));
+    assert!(flag(&control, FLAG_V));
+    assert!(flag(&control, FLAG_C));
+}
+
+#[test]
+fn shift_carry() {
+    let control = run(&[&word(0x8001), &[SHIFT_LEFT_W, 1]]);
+    assert_eq!(stack(&control), vec![0x02, 0x00]);
+    assert!(flag(&control, FLAG_C));
+
+    let control = run(&[&word(0x4001), &[SHIFT_LEFT_W, 1]]);
+    assert_eq!(stack(&control), vec![0x02, 0x80]);
+    assert!(!flag(&control, FLAG_C));
+    assert!(flag(&control, FLAG_N));
+
+    let control = run(&[&word(0x0001), &[SHIFT_RIGHT_W, 1]]);
+    assert_eq!(stack(&control), vec![0x00, 0x00]);
+    assert!(flag(&control, FLAG_C));
+    assert!(flag(&control, FLAG_Z));
+
+    // the last bit shifted out
+    let control = run(&[&word(0x0100), &[SHIFT_RIGHT_W, 9]]);
+    assert_eq!(stack(&control), vec![0x00, 0x00]);
+    assert!(flag(&control, FLAG_C));
+
+    let control = run(&[&word(0x8000), &[SHIFT_LEFT_W, 16]]);
+    assert_eq!(stack(&control), vec![0x00, 0x00]);
+    assert!(!flag(&control, FLAG_C));
+
+    // rotates carry nothing out
+    let control = run(&[&word(0x8001), &[ROTATE_LEFT_W, 1]]);
+    assert_eq!(stack(&control), vec![0x03, 0x00]);
+    assert!(!flag(&control, FLAG_C));
+
+    let control = run(&[&word(0x0001), &[ROTATE_RIGHT_W, 1]]);
+    assert_eq!(stack(&control), vec![0x00, 0x80]);
+    assert!(!flag(&control, FLAG_C));
+    assert!(flag(&control, FLAG_N));
+}
+
+#[test]
+fn divide_by_zero() {
+    // quotient all ones, remainder the low byte of X
+    for &op in &[DIVIDE, DIVIDE_S] {
+        let control = run(&[&byte(0x12), &byte(0), &[op, 0], &[OVERFLOW]]);
+        assert_eq!(stack(&control), vec![0xFF, 0x12]);
+        assert!(flag(&control, FLAG_V));
+    }
+    for &op in &[DIVIDE_W, DIVIDE_WS] {
+        let control = run(&[&word(0x3412), &byte(0), &[op, 0], &[OVERFLOW]]);
+        assert_eq!(stack(&control), vec![0xFF, 0x12]);
+        assert!(flag(&control, FLAG_V));
+    }
+
+    let control = run(&[&byte(0x12), &byte(0), &[MODULO, 0]]);
+    assert_eq!(stack(&control), vec![0x12]);
+    assert!(flag(&control, FLAG_V));
+}
+
+#[test]
+fn divide() {
+    let control = run(&[&byte(200), &byte(7), &[DIVIDE, 0]]);
+    assert_eq!(stack(&control), vec![28]);
+    assert!(flag(&control, FLAG_C));
+    assert!(!flag(&control, FLAG_V));
+
+    let control = run(&[&byte(200), &byte(7), &[MODULO, 0]]);
+    assert_eq!(stack(&control), vec![4]);
+
+    let control = run(&[&word(1000), &byte(10), &[DIVIDE_W, 0]]);
+    assert_eq!(stack(&control), vec![100]);
+    assert!(!flag(&control, FLAG_C));
+    assert!(!flag(&control, FLAG_V));
+
+    // the quotient does not fit in a byte
+    let control = run(&[&word(1000), &byte(3), &[DIVIDE_W, 0]]);
+    assert_eq!(stack(&control), vec![333u16 as u8]);
+    assert!(flag(&control, FLAG_V));
+}
+
+#[test]
+fn signed_divide_overflow() {
+    let control = run(&[&byte(0x80), &byte(0xFF), &[DIVIDE_S, 0]]);
+    assert_eq!(stack(&control), vec![0x80]);
+    assert!(flag(&control, FLAG_V));
+
+    let control = run(&[&word(0x8000), &byte(0xFF), &[DIVIDE_WS, 0]]);
+    assert_eq!(stack(&control), vec![0x00]);
+    assert!(flag(&control, FLAG_V));
+
+    let control = run(&[&word(0xFF80), &byte(0xFF), &[DIVIDE_WS, 0]]);
+    assert_eq!(stack(&control), vec![0x80]);
+    assert!(flag(&control, FLAG_V));
+
+    let control = run(&[&word(0xFF81), &byte(0xFF), &[DIVIDE_WS, 0]]);
+    assert_eq!(stack(&control), vec![0x7F]);
+    assert!(!flag(&control, FLAG_V));
+}
+
+#[test]
+fn signed_remainder() {
+    // the remainder has the sign of X
+    for &(x, y, quotient, remainder) in &[
+        (7i8, 2i8, 3i8, 1i8),
+        (-7, 2, -3, -1),
+        (7, -2, -3, 1),
+        (-7, -2, 3, -1),
+        (-6, 3, -2, 0),
+    ] {
+        let control = run(&[&byte(x as u8), &byte(y as u8), &[DIVIDE_S, 0]]);
+        assert_eq!(stack(&control), vec![quotient as u8], "{} / {}", x, y);
+        assert_eq!(flag(&control, FLAG_N), quotient < 0);
+        assert_eq!(flag(&control, FLAG_C), remainder != 0);
+
+        let control = run(&[&byte(x as u8), &byte(y as u8), &[MODULO_S, 0]]);
+        assert_eq!(stack(&control), vec![remainder as u8], "{} % {}", x, y);
+
+        let x = x as i16 * 100;
+        let control = run(&[&word(x as u16), &byte(y as u8), &[MODULO_WS, 0]]);
+        assert_eq!(stack(&control), vec![(x % y as i16) as u8], "{} % {}", x, y);
+    }
+
+    let control = run(&[&word(-1000i16 as u16), &byte(9), &[MODULO_WS, 0]]);
+    assert_eq!(stack(&control), vec![-1i8 as u8]);
+}
+
+#[test]
+fn signed_multiply() {
+    for &(x, y) in &[(-3i8, 5i8), (12, 10), (-16, 8), (-128, 1), (0, -1)] {
+        let product = x as i16 * y as i16;
+        let control = run(&[&byte(x as u8), &byte(y as u8), &[MULTIPLY_S, 0]]);
+        assert_eq!(stack(&control), vec![product as u8], "{} * {}", x, y);
+        assert!(!flag(&control, FLAG_C), "{} * {}", x, y);
+        assert!(!flag(&control, FLAG_V), "{} * {}", x, y);
+        assert_eq!(flag(&control, FLAG_N), product < 0);
+        assert_eq!(flag(&control, FLAG_Z), product == 0);
+    }
+
+    // the product does not fit in a signed byte
+    for &(x, y) in &[(-128i8, -1i8), (16, 8), (-17, 8), (127, 127), (-128, -128)] {
+        let product = x as i16 * y as i16;
+        let control = run(&[
+            &byte(x as u8),
+            &byte(y as u8),
+            &[MULTIPLY_S, 0],
+            &[OVERFLOW],
+        ]);
+        assert_eq!(
+            stack(&control),
+            vec![product as u8, (product >> 8) as u8],
+            "{} * {}",
+            x,
+            y
+        );
+        assert!(flag(&control, FLAG_C), "{} * {}", x, y);
+        assert!(flag(&control, FLAG_V), "{} * {}", x, y);
+        assert_eq!(flag(&control, FLAG_N), product < 0);
+    }
+}
+
+#[test]
+fn full_multiply() {
+    // the product is pushed low byte first
+    let control = run(&[&byte(200), &byte(200), &[MULTIPLY_D, 0]]);
+    assert_eq!(stack(&control), vec![0x40, 0x9C]);
+
+    let control = run(&[&byte(0xFE), &byte(100), &[MULTIPLY_DS, 0]]);
+    assert_eq!(stack(&control), vec![0x38, 0xFF]);
+    assert!(flag(&control, FLAG_N));
+    assert!(flag(&control, FLAG_C));
+    assert!(flag(&control, FLAG_V));
+
+    let control = run(&[&byte(0xFE), &byte(100), &[MULTIPLY_D, 0]]);
+    assert_eq!(stack(&control), vec![0x38, 0x63]);
+
+    let control = run(&[&byte(0xFF), &byte(0xFF), &[MULTIPLY_DS, 0]]);
+    assert_eq!(stack(&control), vec![0x01, 0x00]);
+    assert!(!flag(&control, FLAG_C));
+    assert!(!flag(&control, FLAG_V));
+
+    // the product is a word the _W operations can take
+    let control = run(&[
+        &byte(0x12),
+        &byte(0x10),
+        &[MULTIPLY_D, 0],
+        &word(0x0001),
+        &[ADD_W, 0],
+    ]);
+    assert_eq!(stack(&control), vec![0x21, 0x01]);
+}
+
+fn bcd(n: u32) -> u8 {
+    (n / 10 % 10 * 16 + n % 10) as u8
+}
+
+// sets or clears C
+fn carry(c: bool) -> [u8; 5] {
+    if c {
+        [IMM_CONST, 0, IMM_CONST, 1, COMPARE]
+    } else {
+        [IMM_CONST, 1, IMM_CONST, 0, COMPARE]
+    }
+}
+
+#[test]
+fn binary_carry_in() {
+    // the carry in makes the sum wrap
+    let control = run(&[&carry(true), &byte(0x80), &byte(0x7F), &[ADD_CARRY]]);
+    assert_eq!(stack(&control), vec![0x00]);
+    assert!(flag(&control, FLAG_C));
+    assert!(flag(&control, FLAG_Z));
+
+    let control = run(&[&carry(true), &byte(0x00), &byte(0x00), &[SUB_BORROW]]);
+    assert_eq!(stack(&control), vec![0xFF]);
+    assert!(flag(&control, FLAG_C));
+
+    let control = run(&[&carry(true), &byte(0x05), &byte(0xFF), &[SUB_BORROW]]);
+    assert_eq!(stack(&control), vec![0x05]);
+    assert!(flag(&control, FLAG_C));
+}
+
+#[test]
+fn decimal_add() {
+    for x in 0..100 {
+        for y in 0..100 {
+            for &c in &[false, true] {
+                let sum = x + y + c as u32;
+                let control = run(&[
+                    &carry(c),
+                    &byte(bcd(x)),
+                    &byte(bcd(y)),
+                    &[ADD_CARRY_DECIMAL, 0],
+                ]);
+                assert_eq!(stack(&control), vec![bcd(sum)], "{} + {} + {}", x, y, c);
+                assert_eq!(flag(&control, FLAG_C), sum >= 100, "{} + {}", x, y);
+                assert_eq!(flag(&control, FLAG_Z), bcd(sum) == 0);
+            }
+        }
+    }
+}
+
+#[test]
+fn decimal_subtract() {
+    for x in 0..100 {
+        for y in 0..100 {
+            for &c in &[false, true] {
+                let difference = (x + 100 - y - c as u32) % 100;
+                let control = run(&[
+                    &carry(c),
+                    &byte(bcd(x)),
+                    &byte(bcd(y)),
+                    &[SUB_BORROW_DECIMAL, 0],
+                ]);
+                assert_eq!(
+                    stack(&control),
+                    vec![bcd(difference)],
+                    "{} - {} - {}",
+                    x,
+                    y,
+                    c
+                );
+                assert_eq!(flag(&control, FLAG_C), x < y + c as u32, "{} - {}", x, y);
+            }
+        }
+    }
+}
+
+#[test]
+fn decimal_chains() {
+    // 99 + 01, then 99 + 66 with the carry
+    let control = run(&[
+        &byte(0x99),
+        &byte(0x01),
+        &[ADD_DECIMAL, 0],
+        &byte(0x99),
+        &byte(0x66),
+        &[ADD_CARRY_DECIMAL, 0],
+    ]);
+    assert_eq!(stack(&control), vec![0x00, 0x66]);
+    assert!(flag(&control, FLAG_C));
+
+    // 9999 + 0001, low bytes first
+    let control = run(&[
+        &byte(0x99),
+        &byte(0x01),
+        &[ADD_DECIMAL, 0],
+        &byte(0x99),
+        &byte(0x00),
+        &[ADD_CARRY_DECIMAL, 0],
+    ]);
+    assert_eq!(stack(&control), vec![0x00, 0x00]);
+    assert!(flag(&control, FLAG_C));
+    assert!(flag(&control, FLAG_Z));
+
+    // 00 - 01, then 05 - 05 with the borrow
+    let control = run(&[
+        &byte(0x00),
+        &byte(0x01),
+        &[SUB_DECIMAL, 0],
+        &byte(0x05),
+        &byte(0x05),
+        &[SUB_BORROW_DECIMAL, 0],
+    ]);
+    assert_eq!(stack(&control), vec![0x99, 0x99]);
+    assert!(flag(&control, FLAG_C));
+
+    // 2000 - 0001, low bytes first
+    let control = run(&[
+        &byte(0x00),
+        &byte(0x01),
+        &[SUB_DECIMAL, 0],
+        &byte(0x20),
+        &byte(0x00),
+        &[SUB_BORROW_DECIMAL, 0],
+    ]);
+    assert_eq!(stack(&control), vec![0x99, 0x19]);
+    assert!(!flag(&control, FLAG_C));
+}
+
+#[test]
+fn half_carry() {
+    let control = run(&[&byte(0x0F), &byte(0x01), &[ADD]]);
+    assert!(flag(&control, FLAG_H));
+
+    let control = run(&[&byte(0x0E), &byte(0x01), &[ADD]]);
+    assert!(!flag(&control, FLAG_H));
+
+    let control = run(&[&byte(0x10), &byte(0x01), &[SUBTRACT]]);
+    assert!(flag(&control, FLAG_H));
+
+    let control = run(&[&byte(0x11), &byte(0x01), &[SUBTRACT]]);
+    assert!(!flag(&control, FLAG_H));
+
+    // other operations clear it
+    let control = run(&[&byte(0x0F), &byte(0x01), &[ADD], &byte(0x01), &[AND]]);
+    assert!(!flag(&control, FLAG_H));
+}
@@ -0,0 +1,109 @@
+use stack85::asm;
+use stack85::control::*;
+
+fn image(source: &str) -> Vec<u8> {
+    match asm::assemble(source) {
+        Ok(assembly) => assembly.image,
+        Err(errors) => panic!("{:?}", errors),
+    }
+}
+
+// The message of the only error assembling `source` reports.
+fn error(source: &str) -> String {
+    match asm::assemble(source) {
+        Ok(_) => panic!("`{}` assembled", source),
+        Err(errors) => {
+            assert_eq!(errors.len(), 1, "{:?}", errors);
+            errors[0].message.clone()
+        }
+    }
+}
+
+#[test]
+fn instructions() {
+    assert_eq!(
+        image("start: imm_const 'A'\n add ; comment\n GOTO start\n"),
+        vec![IMM_CONST, b'A', ADD, GOTO, 0x00, 0x00]
+    );
+}
+
+#[test]
+fn numbers() {
+    assert_eq!(
+        image(".byte 42, 0x2A, 0b101010, '*', -1, '\\n'"),
+        vec![42, 42, 42, 42, 0xFF, b'\n']
+    );
+}
+
+#[test]
+fn expressions() {
+    let source = "
+        .org 0x1234
+here:   .word here + 2 - 1
+        .byte <here, >here, <here + 0x100
+        IMM_CONST_D here - 0x34
+";
+    let image = image(source);
+    assert_eq!(
+        image[0x1234..],
+        [0x35, 0x12, 0x34, 0x12, 0x34, IMM_CONST_D, 0x00, 0x12]
+    );
+}
+
+#[test]
+fn directives() {
+    let assembly = asm::assemble(".org 3\nmsg: .ascii \"hi;\\\"\"\n.word 0xBEEF").unwrap();
+    assert_eq!(
+        assembly.image,
+        vec![0, 0, 0, b'h', b'i', b';', b'"', 0xEF, 0xBE]
+    );
+    assert_eq!(assembly.label("msg"), Some(3));
+    assert_eq!(assembly.label_at(3), Some("msg"));
+}
+
+#[test]
+fn branches() {
+    let source = "
+back:   IMM_BRANCH_S back
+        IMM_BRANCH ahead
+        .byte 0
+ahead:  IMM_BRANCH 5
+";
+    assert_eq!(
+        image(source),
+        vec![IMM_BRANCH_S, 0xFE, IMM_BRANCH, 1, 0, IMM_BRANCH, 5]
+    );
+}
+
+#[test]
+fn undefined_label() {
+    assert_eq!(error("GOTO nowhere"), "undefined label `nowhere`");
+}
+
+#[test]
+fn out_of_range() {
+    assert_eq!(error("IMM_CONST 256"), "256 does not fit in a byte");
+    assert_eq!(error("IMM_CONST -129"), "-129 does not fit in a byte");
+    assert_eq!(error(".word 0x10000"), "65536 does not fit in a word");
+    assert_eq!(error("x: IMM_BRANCH x"), "branch target is -2 bytes away");
+    assert_eq!(error(".org 0x10000"), ".org 0x10000 is beyond 0xFFFF");
+}
+
+#[test]
+fn overflowing_expression() {
+    assert_eq!(
+        error(".byte 0x7FFFFFFFFFFFFFFF + 1"),
+        "expression overflows"
+    );
+    assert_eq!(
+        error(".byte -0x7FFFFFFFFFFFFFFF - 2"),
+        "expression overflows"
+    );
+}
+
+#[test]
+fn bad_source() {
+    assert_eq!(error("FROB"), "unknown mnemonic `FROB`");
+    assert_eq!(error("ADD 1"), "ADD takes no operand");
+    assert_eq!(error("a: WAIT\na: WAIT"), "duplicate label `a`");
+}
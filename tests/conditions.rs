@@ -0,0 +1,80 @@
+use stack85::control;
+use stack85::control::*;
+
+const BOUNDARIES: [u8; 9] = [0x00, 0x01, 0x02, 0x7E, 0x7F, 0x80, 0x81, 0xFE, 0xFF];
+
+// Runs `x op y` followed by `cond` and reports whether the instruction
+// guarded by `cond` was executed.
+fn taken(op: u8, x: u8, y: u8, cond: u8) -> bool {
+    let program = vec![
+        SET_STACK, 0x40, 0x00, IMM_CONST, x, IMM_CONST, y, op, cond, CONST_1, WAIT,
+    ];
+
+    let mut control = control::new();
+    control.load_image(0, &program).unwrap();
+    control.start();
+    control.run();
+
+    assert_eq!(control.fault(), None);
+    let depth = if op == COMPARE { 0x40 } else { 0x41 };
+    control.stack_ptr() == depth + 1
+}
+
+fn check(cond: u8, expected: impl Fn(u8, u8) -> bool) {
+    for &op in &[COMPARE, SUBTRACT] {
+        for &x in &BOUNDARIES {
+            for &y in &BOUNDARIES {
+                assert_eq!(
+                    taken(op, x, y, cond),
+                    expected(x, y),
+                    "{:02X} {} {:02X}",
+                    x,
+                    control::mnemonic(cond).unwrap(),
+                    y
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn if_overflow() {
+    check(IF_OVERFLOW, |x, y| (x as i8).checked_sub(y as i8).is_none());
+}
+
+#[test]
+fn if_no_overflow() {
+    check(IF_NO_OVERFLOW, |x, y| {
+        (x as i8).checked_sub(y as i8).is_some()
+    });
+}
+
+#[test]
+fn if_greater_equal() {
+    check(IF_GREATER_EQUAL, |x, y| x as i8 >= y as i8);
+}
+
+#[test]
+fn if_less_equal() {
+    check(IF_LESS_EQUAL, |x, y| x as i8 <= y as i8);
+}
+
+#[test]
+fn if_greater() {
+    check(IF_GREATER, |x, y| x as i8 > y as i8);
+}
+
+#[test]
+fn if_less() {
+    check(IF_LESS, |x, y| (x as i8) < y as i8);
+}
+
+#[test]
+fn if_higher() {
+    check(IF_HIGHER, |x, y| x > y);
+}
+
+#[test]
+fn if_lower() {
+    check(IF_LOWER, |x, y| x < y);
+}
@@ -0,0 +1,139 @@
+use stack85::debugger::{self, Debugger};
+use stack85::{asm, control};
+
+// pushes 1s forever
+const PUSHER: &str = "
+start:  SET_STACK 0x4000
+loop:   CONST_1
+        GOTO loop
+";
+
+fn debugger(source: &str) -> Debugger {
+    let assembly = asm::assemble(source).unwrap();
+    let mut control = control::new();
+    control.load_image(0, &assembly.image).unwrap();
+    control.start();
+    debugger::new(control, assembly)
+}
+
+// The first line of the output of a command that must succeed.
+fn first_line(debugger: &mut Debugger, line: &str) -> String {
+    let output = debugger.command(line).unwrap();
+    output.lines().next().unwrap_or_default().to_string()
+}
+
+#[test]
+fn bad_commands() {
+    let mut debugger = debugger(PUSHER);
+    let error = |debugger: &mut Debugger, line| debugger.command(line).unwrap_err();
+
+    assert_eq!(
+        error(&mut debugger, "frob"),
+        "unknown command `frob`; try `help`"
+    );
+    assert_eq!(error(&mut debugger, "s 0"), "count must be at least 1");
+    assert_eq!(error(&mut debugger, "b nowhere"), "unknown label `nowhere`");
+    assert_eq!(error(&mut debugger, "set ip"), "usage: set <reg> <value>");
+    assert_eq!(error(&mut debugger, "irq 8"), "there are 8 lines");
+    assert_eq!(
+        error(&mut debugger, "watch w"),
+        "usage: watch <r|w|c> <addr> [len]"
+    );
+    assert_eq!(error(&mut debugger, "watch q 1"), "unknown watch kind `q`");
+    assert_eq!(
+        error(&mut debugger, "watch w 0x10 0xFFFFFFFF"),
+        "watched range wraps around"
+    );
+    assert_eq!(
+        error(&mut debugger, "watch w 0xFFFF 2"),
+        "watched range wraps around"
+    );
+    assert_eq!(debugger.command("").unwrap(), "");
+    assert_eq!(debugger.control().instr_ptr(), 0);
+}
+
+#[test]
+fn addresses() {
+    let debugger = debugger(PUSHER);
+    assert_eq!(debugger.address("loop"), Ok(3));
+    assert_eq!(debugger.address("loop+0x10"), Ok(0x13));
+    assert_eq!(debugger.address("loop-1"), Ok(2));
+    assert_eq!(debugger.address("42"), Ok(42));
+}
+
+#[test]
+fn breakpoints() {
+    let mut debugger = debugger(PUSHER);
+    assert_eq!(
+        first_line(&mut debugger, "b loop"),
+        "breakpoint at 0003 <loop>"
+    );
+    assert_eq!(first_line(&mut debugger, "b"), "0003 <loop>");
+    assert_eq!(first_line(&mut debugger, "c"), "breakpoint at 0003 <loop>");
+
+    // continuing leaves the breakpoint and comes round to it again
+    assert_eq!(first_line(&mut debugger, "c"), "breakpoint at 0003 <loop>");
+    assert_eq!(debugger.control().stack_ptr(), 0x4001);
+
+    assert_eq!(
+        first_line(&mut debugger, "d loop"),
+        "deleted breakpoint at 0003 <loop>"
+    );
+    assert_eq!(
+        debugger.command("d loop"),
+        Err("no breakpoint at 0003 <loop>".to_string())
+    );
+    debugger.command("c 10").unwrap();
+    assert_eq!(debugger.control().instr_ptr(), 3);
+    assert_eq!(debugger.control().stack_ptr(), 0x4006);
+}
+
+#[test]
+fn write_watchpoint() {
+    let mut debugger = debugger(PUSHER);
+    assert_eq!(
+        first_line(&mut debugger, "watch w 0x4002 2"),
+        "watching write 4002-4003"
+    );
+    assert_eq!(
+        first_line(&mut debugger, "c"),
+        "watchpoint: write 4002: 00 -> 01"
+    );
+    assert_eq!(
+        first_line(&mut debugger, "c"),
+        "watchpoint: write 4003: 00 -> 01"
+    );
+    assert_eq!(
+        first_line(&mut debugger, "unwatch w 0x4002 2"),
+        "removed write 4002-4003"
+    );
+    assert_eq!(first_line(&mut debugger, "watch"), "no watchpoints");
+}
+
+#[test]
+fn change_watchpoint() {
+    let mut debugger = debugger(PUSHER);
+    first_line(&mut debugger, "deposit 0x4001 1");
+    first_line(&mut debugger, "watch c 0x4001 2");
+
+    // pushing 1 over the 1 at 4001 changes nothing
+    assert_eq!(
+        first_line(&mut debugger, "c"),
+        "watchpoint: change 4002: 00 -> 01"
+    );
+    assert_eq!(debugger.control().stack_ptr(), 0x4002);
+}
+
+#[test]
+fn waiting_for_an_interrupt() {
+    let mut debugger = debugger("ENABLE_INT 1\nWAIT\nGOTO 0\n");
+    assert_eq!(
+        first_line(&mut debugger, "c"),
+        "waiting for interrupt at 0003"
+    );
+    assert_eq!(first_line(&mut debugger, "c 5"), "waiting for an interrupt");
+    assert_eq!(first_line(&mut debugger, "n"), "waiting for an interrupt");
+
+    first_line(&mut debugger, "irq 0");
+    assert!(debugger.control().is_running());
+}
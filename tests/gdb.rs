@@ -0,0 +1,100 @@
+use stack85::control::{self, *};
+use stack85::gdb;
+use std::io::Cursor;
+
+// Frames `data` as a packet, with GDB's ack for the reply it gets.
+fn packet(data: &str) -> String {
+    let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    format!("${}#{:02x}+", data, sum)
+}
+
+// Splits the stub's output into acks and reply packets, checking each
+// reply's checksum.
+fn replies(output: &[u8]) -> Vec<String> {
+    let text = std::str::from_utf8(output).unwrap();
+    let mut replies = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '+' | '-' => {
+                replies.push(c.to_string());
+                rest = &rest[1..];
+            }
+            '$' => {
+                let end = rest.find('#').unwrap();
+                let data = &rest[1..end];
+                let sum = u8::from_str_radix(&rest[end + 1..end + 3], 16).unwrap();
+                assert_eq!(
+                    sum,
+                    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b)),
+                    "{}",
+                    data
+                );
+                replies.push(data.to_string());
+                rest = &rest[end + 3..];
+            }
+            _ => panic!("unexpected output {:?}", rest),
+        }
+    }
+    replies
+}
+
+#[test]
+fn session() {
+    // push 1s forever
+    let program = [SET_STACK, 0x00, 0x40, CONST_1, GOTO, 0x03, 0x00];
+    let mut control = control::new();
+    control.load_image(0, &program).unwrap();
+    control.start();
+
+    let mut input = "$g#00".to_string();
+    for data in [
+        "g",
+        "M10,2:abcd",
+        "m10,2",
+        "Z0,4,1",
+        "c",
+        "z0,4,1",
+        "Z2,4003,1",
+        "c",
+        "g",
+        "z2,4003,1",
+        "c",
+    ] {
+        input += &packet(data);
+    }
+    input += "\x03+";
+    input += &packet("k");
+
+    let mut output = Vec::new();
+    let control = gdb::session(control, Cursor::new(input.into_bytes()), &mut output).unwrap();
+
+    let registers = "0000".repeat(4) + &"00".repeat(5);
+    let replies = replies(&output);
+    let expected = [
+        "-",
+        "+",
+        &registers,
+        "+",
+        "OK",
+        "+",
+        "abcd",
+        "+",
+        "OK",
+        "+",
+        "T05swbreak:;",
+        "+",
+        "OK",
+        "+",
+        "OK",
+        "+",
+        "T05watch:4003;",
+        "+",
+    ];
+    assert_eq!(replies[..expected.len()], expected);
+
+    // IP 0004 and SP 4003 after the third push
+    assert!(replies[expected.len()].starts_with("04000340"));
+    assert_eq!(replies[expected.len() + 1..], ["+", "OK", "+", "S02", "+"]);
+    assert_eq!(control.memory().public_read(0x10), Ok(0xAB));
+}
@@ -0,0 +1,103 @@
+use stack85::control::{self, *};
+
+// Everything a step can change that the tests look at.
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    registers: Registers,
+    int_mask: u8,
+    pending: u8,
+    running: bool,
+    fault: Option<Fault>,
+    ram: Vec<u8>,
+}
+
+fn snapshot(control: &Control) -> Snapshot {
+    Snapshot {
+        registers: control.registers(),
+        int_mask: control.int_mask(),
+        pending: control.pending_irqs(),
+        running: control.is_running(),
+        fault: control.fault(),
+        ram: control.memory().ram().to_vec(),
+    }
+}
+
+// Pushes until the stack limit faults, taking an interrupt on the way.
+fn machine() -> Control {
+    let program = [
+        SET_STACK, 0x00, 0x40, IMM_CONST, 0x7F, IMM_CONST, 0x01, ADD, ENABLE_INT, 0x01, CONST_1,
+    ];
+    let mut control = control::new();
+    control.load_image(0, &program).unwrap();
+    control.load_image(0x20, &[CONST_0, CONST_0]).unwrap();
+    control.load_image(DEFAULT_VECTORS, &[0x20, 0x00]).unwrap();
+    control.set_stack_limit(Some(0x4008));
+    control.start();
+    control
+}
+
+#[test]
+fn step_back_restores_state() {
+    let mut control = machine();
+    control.set_history(100);
+
+    let mut snapshots = Vec::new();
+    while control.is_running() {
+        if control.int_mask() != 0 {
+            control.raise_irq(0);
+        }
+        snapshots.push(snapshot(&control));
+        control.step();
+    }
+    assert_eq!(
+        control.fault().map(|f| f.kind),
+        Some(FaultKind::StackOverflow(0x4008))
+    );
+    assert_eq!(control.history_len(), snapshots.len());
+
+    while let Some(before) = snapshots.pop() {
+        assert!(control.step_back().is_some());
+        assert_eq!(snapshot(&control), before);
+    }
+    assert_eq!(control.step_back(), None);
+}
+
+#[test]
+fn step_back_undoes_an_interrupt() {
+    let mut control = machine();
+    control.set_history(100);
+    for _ in 0..5 {
+        control.step();
+    }
+    assert_eq!(control.int_mask(), 0x01);
+    control.raise_irq(0);
+    let before = snapshot(&control);
+
+    control.step();
+    assert_eq!(control.instr_ptr(), 0x20);
+    assert_eq!(control.int_mask(), 0);
+
+    let writes = control.step_back().unwrap();
+    assert_eq!(writes.len(), 6);
+    assert_eq!(snapshot(&control), before);
+}
+
+#[test]
+fn history_keeps_the_newest_steps() {
+    let mut control = machine();
+    control.set_history(3);
+
+    let mut snapshots = Vec::new();
+    for _ in 0..5 {
+        snapshots.push(snapshot(&control));
+        control.step();
+    }
+    assert_eq!(control.history_len(), 3);
+
+    for before in snapshots[2..].iter().rev() {
+        assert!(control.step_back().is_some());
+        assert_eq!(&snapshot(&control), before);
+    }
+    assert_eq!(control.step_back(), None);
+    assert_eq!(control.history_len(), 0);
+}
@@ -0,0 +1,56 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Runs programs/`name` under stack85-run with `input` piped to the
+// console, returning what it printed and whether it exited successfully.
+fn run(name: &str, input: &[u8]) -> (Vec<u8>, bool) {
+    let program = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("programs")
+        .join(name);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_stack85-run"))
+        .arg(&program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // closing stdin ends the console input
+    child.stdin.take().unwrap().write_all(input).unwrap();
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > TIMEOUT {
+            child.kill().unwrap();
+            panic!("{} did not finish", name);
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let mut output = Vec::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_end(&mut output)
+        .unwrap();
+    (output, status.success())
+}
+
+#[test]
+fn irq_echo() {
+    assert_eq!(run("irq_echo.s", b"abc"), (b"abc".to_vec(), true));
+    assert_eq!(run("irq_echo.s", b""), (Vec::new(), true));
+}
+
+#[test]
+fn echo() {
+    assert_eq!(run("echo.s", b"hello\n"), (b"hello\n".to_vec(), true));
+}
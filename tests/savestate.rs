@@ -0,0 +1,87 @@
+use stack85::config;
+use stack85::control::{self, *};
+use std::io::ErrorKind;
+
+const MACHINE: &str = "
+memory 0x1000
+banks 0x800 0x100 4 0xFFF
+stack 0x100 0x1FF
+";
+
+fn machine() -> Control {
+    control::from_config(&config::parse(MACHINE).unwrap())
+}
+
+fn save(control: &Control) -> Vec<u8> {
+    let mut state = Vec::new();
+    control.save_state(&mut state).unwrap();
+    state
+}
+
+// The message of the error loading `state` into a fresh machine, which
+// must be left untouched.
+fn rejection(state: &[u8]) -> String {
+    let mut control = machine();
+    let before = save(&control);
+    let error = control.load_state(&mut &state[..]).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert_eq!(save(&control), before);
+    error.to_string()
+}
+
+#[test]
+fn round_trip() {
+    let mut control = machine();
+    control.load_image(0, &[IMM_CONST, 7, ADD]).unwrap();
+    control.memory_mut().select_bank(2);
+    control.memory_mut().public_write(0xAB, 0x810).unwrap();
+    control.memory_mut().select_bank(3);
+    control.set_int_mask(0b0001);
+    control.raise_irq(2);
+    control.start();
+    control.run();
+    assert!(control.fault().is_some());
+
+    let state = save(&control);
+    let mut loaded = machine();
+    loaded.load_state(&mut &state[..]).unwrap();
+
+    assert_eq!(loaded.registers(), control.registers());
+    assert_eq!(
+        loaded.fault(),
+        Some(Fault {
+            ip: 2,
+            kind: FaultKind::StackUnderflow(0x101),
+        })
+    );
+    assert_eq!(loaded.stack_base(), 0x100);
+    assert_eq!(loaded.stack_limit(), Some(0x1FF));
+    assert_eq!(loaded.int_mask(), 0b0001);
+    assert_eq!(loaded.pending_irqs(), 0b0100);
+    assert_eq!(loaded.memory().bank(), 3);
+    assert_eq!(loaded.memory().bank_contents(2).unwrap()[0x10], 0xAB);
+    assert_eq!(loaded.memory().ram(), control.memory().ram());
+    assert_eq!(save(&loaded), state);
+}
+
+#[test]
+fn bad_magic() {
+    let mut state = save(&machine());
+    state[0] = b'X';
+    assert_eq!(rejection(&state), "not a stack85 save state");
+}
+
+#[test]
+fn bad_version() {
+    let mut state = save(&machine());
+    state[4] = 99;
+    assert_eq!(rejection(&state), "unsupported save state version 99");
+}
+
+#[test]
+fn bad_flag() {
+    // running follows the magic, version, 4 words and 4 save registers
+    let mut state = save(&machine());
+    state[17] = 2;
+    assert_eq!(rejection(&state), "bad flag in save state");
+}
@@ -0,0 +1,114 @@
+use stack85::control::*;
+use stack85::trace::{self, Divergence, Step};
+use std::io;
+
+fn steps() -> Vec<Step> {
+    vec![
+        Step {
+            ip: 0x0000,
+            interrupt: false,
+            bytes: vec![IMM_CONST, 0x41],
+            sp_before: 0x0100,
+            sp_after: 0x0101,
+            flags: 0,
+            writes: vec![(0x0101, 0x41)],
+        },
+        // wider than a signed byte delta
+        Step {
+            ip: 0x0002,
+            interrupt: false,
+            bytes: vec![SET_STACK, 0x00, 0x40],
+            sp_before: 0x0101,
+            sp_after: 0x4000,
+            flags: 0b000100,
+            writes: Vec::new(),
+        },
+        Step {
+            ip: 0x0005,
+            interrupt: true,
+            bytes: Vec::new(),
+            sp_before: 0x4000,
+            sp_after: 0x4006,
+            flags: 0b000001,
+            writes: vec![
+                (0x4001, 0x05),
+                (0x4002, 0x00),
+                (0x4003, 0x00),
+                (0x4004, 0x00),
+                (0x4005, 0x01),
+                (0x4006, 0x02),
+            ],
+        },
+    ]
+}
+
+fn write(steps: &[Step]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut writer = trace::writer(&mut output).unwrap();
+    for step in steps {
+        writer.write_step(step).unwrap();
+    }
+    output
+}
+
+fn diff(left: &[Step], right: &[Step]) -> Option<Divergence> {
+    let left = left.iter().cloned().map(Ok);
+    let right = right.iter().cloned().map(Ok);
+    trace::diff(left, right).unwrap()
+}
+
+#[test]
+fn round_trip() {
+    let steps = steps();
+    let bytes = write(&steps);
+    assert_eq!(&bytes[..5], b"S85T\x01");
+    // tag of the second record: wide SP, no interrupt
+    assert_eq!(bytes[5 + 14], 0b10);
+
+    let read: Vec<Step> = trace::reader(&bytes[..])
+        .unwrap()
+        .collect::<io::Result<_>>()
+        .unwrap();
+    assert_eq!(read, steps);
+}
+
+#[test]
+fn bad_header() {
+    let error = trace::reader(&b"S85S\x01"[..]).err().unwrap();
+    assert_eq!(error.to_string(), "not a stack85 trace");
+    let error = trace::reader(&b"S85T\x09"[..]).err().unwrap();
+    assert_eq!(error.to_string(), "unsupported trace version 9");
+}
+
+#[test]
+fn identical() {
+    assert_eq!(diff(&steps(), &steps()), None);
+}
+
+#[test]
+fn divergence() {
+    let left = steps();
+    let mut right = steps();
+    right[1].sp_after = 0x3000;
+    right[1].flags = 0;
+
+    let divergence = diff(&left, &right).unwrap();
+    assert_eq!(divergence.index, 1);
+    assert_eq!(divergence.left.as_ref(), Some(&left[1]));
+    assert_eq!(divergence.right.as_ref(), Some(&right[1]));
+    assert_eq!(divergence.fields(), vec!["sp", "flags"]);
+
+    right = steps();
+    right[2].interrupt = false;
+    right[2].writes.pop();
+    assert_eq!(diff(&left, &right).unwrap().fields(), vec!["ip", "writes"]);
+}
+
+#[test]
+fn one_trace_ends_first() {
+    let left = steps();
+    let divergence = diff(&left, &left[..2]).unwrap();
+    assert_eq!(divergence.index, 2);
+    assert_eq!(divergence.right, None);
+    assert_eq!(divergence.fields(), vec!["length"]);
+}